@@ -1,14 +1,18 @@
 export function loadInitialCanvasData(
   inputData: ArrayBufferLike,
-  ctx: CanvasRenderingContext2D
+  ctx: CanvasRenderingContext2D,
+  width: number,
+  height: number
 ) {
   let setCount = 0;
-  const imgData = ctx.createImageData(1000, 1000);
+  const imgData = ctx.createImageData(width, height);
   let byteInputData = new Uint8Array(inputData);
   const data = imgData.data;
   let bit_index = 0;
   let byte_index = 0;
-  for (let i = 0; i < byteInputData.length * 8; i++) {
+  // The board may hold more bits than the canvas shows
+  const pixels = Math.min(byteInputData.length * 8, width * height);
+  for (let i = 0; i < pixels; i++) {
     bit_index = i % 8;
     byte_index = Math.floor(i / 8);
     let byte_value = byteInputData[byte_index];
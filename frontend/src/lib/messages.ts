@@ -0,0 +1,36 @@
+// What to show for the server's mode event, nothing while the board is open
+export function modeText(event: { mode: string; notice?: string }): string {
+  if (event.mode === "open") {
+    return "";
+  }
+  if (event.notice) {
+    return event.notice;
+  }
+  if (event.mode === "read-only") {
+    return "The board is read-only";
+  }
+  return "The board is down for maintenance";
+}
+
+// What to show for a rejected write
+export function rejectedText(event: {
+  reason: string;
+  retry_after_ms?: number;
+}): string {
+  switch (event.reason) {
+    case "rate_limited":
+      return `Too fast, try again in ${Math.ceil((event.retry_after_ms ?? 0) / 1000)}s`;
+    case "too_large":
+      return "Too many pixels at once";
+    case "read_only":
+      return "The board is read-only";
+    case "maintenance":
+      return "The board is down for maintenance";
+    case "protected":
+      return "This part of the board is protected";
+    case "banned":
+      return "You can't draw here";
+    default:
+      return `Write rejected: ${event.reason}`;
+  }
+}
@@ -0,0 +1,464 @@
+use std::{io, net::IpAddr, sync::atomic::Ordering};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use image::{ImageError, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{Entry, Filter},
+    grid::{Canvas, Rect},
+    mode::{BoardMode, Mode},
+    regions::{opaque_mask, Region},
+    rollback::{Point, Reverted},
+    state::{read_dump, AppState},
+    tiles::{encode_png, render, Window},
+    write::Applied,
+};
+
+/// Operator endpoints, nested under `/admin`. Every request needs the
+/// configured token as `Authorization: Bearer <token>`.
+pub fn router(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/rect", post(rect))
+        .route("/stamp", post(stamp))
+        .route("/mode", get(mode).post(set_mode))
+        .route("/freeze", post(freeze))
+        .route("/unfreeze", post(unfreeze))
+        .route("/save", post(save))
+        .route("/load", post(load))
+        .route("/stats", get(stats))
+        .route("/audit", get(audit))
+        .route("/revert", post(revert))
+        .route("/rollback", get(rollback_preview).post(rollback))
+        .route("/regions", get(regions).post(protect))
+        .route("/regions/mask", post(protect_mask))
+        .route("/regions/:id", delete(unprotect))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+}
+
+async fn authorize(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    // Without a token the API doesn't exist
+    let Some(token) = &state.config.admin_token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !given.is_some_and(|given| same_secret(given.as_bytes(), token.as_bytes())) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Compares without returning early, so timing doesn't give the token away.
+fn same_secret(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub enum AdminError {
+    /// The rectangle or position is not on the canvas
+    OutOfRange,
+    NotFound,
+    Invalid(String),
+    Image(ImageError),
+    Io(io::Error),
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            AdminError::OutOfRange => (StatusCode::BAD_REQUEST, "Not on the canvas".to_owned()),
+            AdminError::NotFound => (StatusCode::NOT_FOUND, "Not found".to_owned()),
+            AdminError::Invalid(reason) => (StatusCode::BAD_REQUEST, reason),
+            AdminError::Image(err) => (StatusCode::BAD_REQUEST, err.to_string()),
+            AdminError::Io(err) => {
+                let status = match err.kind() {
+                    io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                    io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => {
+                        StatusCode::BAD_REQUEST
+                    }
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (status, err.to_string())
+            }
+        };
+        (status, message).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct Fill {
+    #[serde(flatten)]
+    rect: Rect,
+    value: bool,
+}
+
+/// Fills a rectangle, or clears it with `"value": false`.
+async fn rect(
+    State(state): State<AppState>,
+    Json(Fill { rect, value }): Json<Fill>,
+) -> Result<Json<Applied>, AdminError> {
+    let canvas = state.config.canvas;
+    if !rect.fits(canvas) {
+        return Err(AdminError::OutOfRange);
+    }
+    let applied = state
+        .overwrite(rect.indices(canvas).map(|index| (index, value)))
+        .await;
+    log::info!("Admin set {:?} to {}", rect, value);
+    Ok(Json(applied))
+}
+
+#[derive(Deserialize)]
+struct Position {
+    x: usize,
+    y: usize,
+}
+
+/// Draws the image in the request body with its top left corner at `x`,
+/// `y`. Dark pixels are set, light ones cleared and transparent ones left
+/// alone. Whatever falls off the canvas is cut.
+async fn stamp(
+    State(state): State<AppState>,
+    Query(Position { x, y }): Query<Position>,
+    body: Bytes,
+) -> Result<Json<Applied>, AdminError> {
+    let canvas = state.config.canvas;
+    if canvas.index(x, y).is_none() {
+        return Err(AdminError::OutOfRange);
+    }
+    let image = image::load_from_memory(&body)
+        .map_err(AdminError::Image)?
+        .to_rgba8();
+    let applied = state.overwrite(stamp_pixels(&image, x, y, canvas)).await;
+    log::info!(
+        "Admin stamped a {}x{} image at {}, {}",
+        image.width(),
+        image.height(),
+        x,
+        y
+    );
+    Ok(Json(applied))
+}
+
+fn stamp_pixels(image: &RgbaImage, x: usize, y: usize, canvas: Canvas) -> Vec<(usize, bool)> {
+    image
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel[3] >= 128)
+        .filter_map(|(px, py, pixel)| {
+            let index = canvas.index(x + px as usize, y + py as usize)?;
+            let [r, g, b, _] = pixel.0.map(u32::from);
+            let luma = (299 * r + 587 * g + 114 * b) / 1000;
+            Some((index, luma < 128))
+        })
+        .collect()
+}
+
+async fn mode(State(state): State<AppState>) -> Json<BoardMode> {
+    Json(state.mode.borrow().clone())
+}
+
+/// Opens or closes the board, connected clients hear about it right away.
+async fn set_mode(State(state): State<AppState>, Json(mode): Json<BoardMode>) -> Json<BoardMode> {
+    state.set_mode(mode.clone()).await;
+    Json(mode)
+}
+
+/// Shorthand for switching to read-only without a notice.
+async fn freeze(State(state): State<AppState>) -> Json<BoardMode> {
+    set_mode(State(state), Json(Mode::ReadOnly.into())).await
+}
+
+async fn unfreeze(State(state): State<AppState>) -> Json<BoardMode> {
+    set_mode(State(state), Json(Mode::Open.into())).await
+}
+
+#[derive(Serialize)]
+struct Saved {
+    seq: u64,
+}
+
+/// Writes the dump and PNG snapshot right away.
+async fn save(State(state): State<AppState>) -> Result<Json<Saved>, AdminError> {
+    let seq = state.dump().await.map_err(AdminError::Io)?;
+    log::info!("Admin saved the board at seq {}", seq);
+    Ok(Json(Saved { seq }))
+}
+
+#[derive(Deserialize)]
+struct Source {
+    /// Dump file to read, the configured one by default
+    path: Option<String>,
+}
+
+/// Brings the board back to a dump, broadcasting the pixels that differ.
+async fn load(
+    State(state): State<AppState>,
+    Query(Source { path }): Query<Source>,
+) -> Result<Json<Applied>, AdminError> {
+    let path = path.unwrap_or_else(|| state.dump_path.clone());
+    let board = read_dump(&path).map_err(AdminError::Io)?;
+    let applied = state.replace_board(&board).await;
+    log::warn!("Admin loaded {}, {} pixels changed", path, applied.changed);
+    Ok(Json(applied))
+}
+
+async fn regions(State(state): State<AppState>) -> Json<Vec<Region>> {
+    Json(state.regions.read().await.list().to_vec())
+}
+
+#[derive(Deserialize)]
+struct NewRegion {
+    #[serde(flatten)]
+    rect: Rect,
+    name: Option<String>,
+}
+
+/// Protects a rectangle from regular writes.
+async fn protect(
+    State(state): State<AppState>,
+    Json(NewRegion { rect, name }): Json<NewRegion>,
+) -> Result<Json<Region>, AdminError> {
+    add_region(&state, name, rect, None).await
+}
+
+#[derive(Deserialize)]
+struct MaskAt {
+    x: usize,
+    y: usize,
+    name: Option<String>,
+}
+
+/// Protects the opaque pixels of the image in the request body, placed
+/// with its top left corner at `x`, `y`.
+async fn protect_mask(
+    State(state): State<AppState>,
+    Query(MaskAt { x, y, name }): Query<MaskAt>,
+    body: Bytes,
+) -> Result<Json<Region>, AdminError> {
+    let image = image::load_from_memory(&body)
+        .map_err(AdminError::Image)?
+        .to_rgba8();
+    let rect = Rect {
+        x,
+        y,
+        width: image.width() as usize,
+        height: image.height() as usize,
+    };
+    add_region(&state, name, rect, Some(opaque_mask(&image))).await
+}
+
+async fn add_region(
+    state: &AppState,
+    name: Option<String>,
+    rect: Rect,
+    mask: Option<String>,
+) -> Result<Json<Region>, AdminError> {
+    let mut regions = state.regions.write().await;
+    let region = regions.add(name, rect, mask).map_err(AdminError::Invalid)?;
+    if let Err(err) = state.save_regions(&regions) {
+        regions.remove(region.id);
+        return Err(AdminError::Io(err));
+    }
+    log::warn!("Admin protected region {} at {:?}", region.id, region.rect);
+    Ok(Json(region))
+}
+
+async fn unprotect(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, AdminError> {
+    let mut regions = state.regions.write().await;
+    let region = regions.remove(id).ok_or(AdminError::NotFound)?;
+    if let Err(err) = state.save_regions(&regions) {
+        regions.restore(region);
+        return Err(AdminError::Io(err));
+    }
+    log::warn!("Admin removed protected region {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct Limit {
+    /// Most entries returned, the newest ones win
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    1000
+}
+
+/// Logged changes, filtered by area, time window and client.
+async fn audit(
+    State(state): State<AppState>,
+    Query(filter): Query<Filter>,
+    Query(Limit { limit }): Query<Limit>,
+) -> Result<Json<Vec<Entry>>, AdminError> {
+    filter.check().map_err(AdminError::Invalid)?;
+    let entries = state
+        .audit_entries(filter, limit)
+        .await
+        .map_err(AdminError::Io)?;
+    Ok(Json(entries))
+}
+
+/// Takes back what one address or session changed, within an optional
+/// time window and area.
+async fn revert(
+    State(state): State<AppState>,
+    Json(filter): Json<Filter>,
+) -> Result<Json<Reverted>, AdminError> {
+    if filter.ip.is_none() && filter.session.is_none() {
+        return Err(AdminError::Invalid("Need an ip or a session".to_owned()));
+    }
+    filter.check().map_err(AdminError::Invalid)?;
+    let reverted = state.revert(filter.clone()).await.map_err(AdminError::Io)?;
+    log::warn!(
+        "Admin reverted {:?}, {} pixels restored, {} changed since",
+        filter,
+        reverted.changed,
+        reverted.skipped
+    );
+    Ok(Json(reverted))
+}
+
+/// The board as it was at `at` or `seq`, as PNG, to look at before
+/// rolling back.
+async fn rollback_preview(
+    State(state): State<AppState>,
+    Query(point): Query<Point>,
+) -> Result<Response, AdminError> {
+    let target = point.target(state.epoch).map_err(AdminError::Invalid)?;
+    let board = state.board_at(target).await.map_err(AdminError::Io)?;
+    let canvas = state.config.canvas;
+    let image = render(&board, canvas, state.config.colors, Window::full(canvas));
+    let png = encode_png(&image).map_err(AdminError::Image)?;
+    Ok(([(header::CONTENT_TYPE, "image/png")], png).into_response())
+}
+
+/// Brings the whole board back to `at` or `seq`, clients get it as one
+/// snapshot.
+async fn rollback(
+    State(state): State<AppState>,
+    Query(point): Query<Point>,
+) -> Result<Json<Applied>, AdminError> {
+    let target = point.target(state.epoch).map_err(AdminError::Invalid)?;
+    let applied = state.roll_back(target).await.map_err(AdminError::Io)?;
+    log::warn!(
+        "Admin rolled the board back to {:?}, {} pixels changed",
+        target,
+        applied.changed
+    );
+    Ok(Json(applied))
+}
+
+#[derive(Serialize)]
+struct Stats {
+    epoch: u64,
+    /// Last write applied
+    seq: u64,
+    /// Last batch broadcast
+    batch_seq: u64,
+    /// Pixels waiting for the next batch
+    queued: usize,
+    history: usize,
+    sessions: usize,
+    addresses: usize,
+    /// Websocket and event stream clients
+    subscribers: usize,
+    /// Addresses with the most websocket sessions
+    busiest: Vec<Busiest>,
+    mode: Mode,
+    /// Protected regions
+    regions: usize,
+}
+
+#[derive(Serialize)]
+struct Busiest {
+    ip: IpAddr,
+    sessions: usize,
+}
+
+async fn stats(State(state): State<AppState>) -> Json<Stats> {
+    let queued = state.queue.lock().await.len();
+    let (batch_seq, history) = {
+        let history = state.history.read().await;
+        (history.last_seq(), history.len())
+    };
+    let (sessions, addresses, busiest) = {
+        let connections = state.connections.lock().await;
+        let busiest = connections
+            .busiest(10)
+            .into_iter()
+            .map(|(ip, sessions)| Busiest { ip, sessions })
+            .collect();
+        (connections.count(), connections.addresses(), busiest)
+    };
+    let mode = state.mode.borrow().mode;
+    Json(Stats {
+        epoch: state.epoch,
+        seq: state.seq.load(Ordering::SeqCst),
+        batch_seq,
+        queued,
+        history,
+        sessions,
+        addresses,
+        subscribers: state.broadcast.lock().await.receiver_count(),
+        busiest,
+        regions: state.regions.read().await.list().len(),
+        mode,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn secrets_compare_whole() {
+        assert!(same_secret(b"0123456789abcdef", b"0123456789abcdef"));
+        assert!(!same_secret(b"0123456789abcdeF", b"0123456789abcdef"));
+        assert!(!same_secret(b"0123456789abcde", b"0123456789abcdef"));
+        assert!(!same_secret(b"", b"0123456789abcdef"));
+    }
+
+    #[test]
+    fn stamp_sets_dark_and_skips_transparent() {
+        let canvas = Canvas {
+            width: 10,
+            height: 5,
+        };
+        let mut image = RgbaImage::new(3, 1);
+        image.put_pixel(0, 0, Rgba([0, 0, 0, 255]));
+        image.put_pixel(1, 0, Rgba([255, 255, 255, 255]));
+        image.put_pixel(2, 0, Rgba([0, 0, 0, 0]));
+        assert_eq!(
+            vec![(41, true), (42, false)],
+            stamp_pixels(&image, 1, 4, canvas)
+        );
+        // Cut at the right edge
+        assert_eq!(vec![(49, true)], stamp_pixels(&image, 9, 4, canvas));
+    }
+}
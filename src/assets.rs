@@ -0,0 +1,150 @@
+use std::path::Path;
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use tower_http::services::{ServeDir, ServeFile};
+
+use crate::state::AppState;
+
+/// Scripts and styles of the frontend build, a missing one is an error
+/// rather than a client side route
+const APP_PREFIX: &str = "/_app/";
+/// Build output named by content hash and never changed
+const IMMUTABLE_PREFIX: &str = "/_app/immutable/";
+
+/// Serves the frontend build from `dir`, falling back to `index.html` so
+/// client side routes load the app. Precompressed `.br` and `.gz` siblings
+/// are sent to clients that accept them.
+pub fn directory(dir: &Path) -> Router<AppState> {
+    let app = ServeDir::new(dir.join(APP_PREFIX.trim_matches('/')))
+        .precompressed_br()
+        .precompressed_gzip();
+    let index = ServeFile::new(dir.join("index.html"))
+        .precompressed_br()
+        .precompressed_gzip();
+    let files = ServeDir::new(dir)
+        .precompressed_br()
+        .precompressed_gzip()
+        .fallback(index);
+    Router::new()
+        .nest_service(APP_PREFIX.trim_end_matches('/'), app)
+        .fallback_service(files)
+        .layer(middleware::from_fn(cache_headers))
+}
+
+/// Lets browsers keep hashed assets forever and revalidate everything else.
+async fn cache_headers(request: Request, next: Next) -> Response {
+    let immutable = request.uri().path().starts_with(IMMUTABLE_PREFIX);
+    let mut response = next.run(request).await;
+    if response.status().is_success() || response.status().is_redirection() {
+        response
+            .headers_mut()
+            .insert(header::CACHE_CONTROL, cache_control(immutable));
+    }
+    response
+}
+
+fn cache_control(immutable: bool) -> HeaderValue {
+    if immutable {
+        HeaderValue::from_static("public, max-age=31536000, immutable")
+    } else {
+        HeaderValue::from_static("no-cache")
+    }
+}
+
+/// The frontend compiled into the binary with the `embed` feature.
+#[cfg(feature = "embed")]
+pub mod embedded {
+    use axum::{
+        http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+        response::{IntoResponse, Response},
+        Router,
+    };
+    use rust_embed::{EmbeddedFile, RustEmbed};
+
+    use super::{cache_control, APP_PREFIX, IMMUTABLE_PREFIX};
+    use crate::{encoding::ContentCoding, server::etag_matches, state::AppState};
+
+    #[derive(RustEmbed)]
+    #[folder = "frontend/build"]
+    struct Assets;
+
+    pub fn router() -> Router<AppState> {
+        Router::new().fallback(asset)
+    }
+
+    async fn asset(uri: Uri, headers: HeaderMap) -> Response {
+        let path = match uri.path().trim_start_matches('/') {
+            "" => "index.html",
+            path => path,
+        };
+        let (path, file) = match Assets::get(path) {
+            Some(file) => (path, file),
+            None if uri.path().starts_with(APP_PREFIX) => {
+                return StatusCode::NOT_FOUND.into_response()
+            }
+            None => match Assets::get("index.html") {
+                Some(file) => ("index.html", file),
+                None => return StatusCode::NOT_FOUND.into_response(),
+            },
+        };
+
+        let etag = format!("\"{}\"", hex(&file.metadata.sha256_hash()));
+        let immutable = uri.path().starts_with(IMMUTABLE_PREFIX);
+        if etag_matches(&headers, &etag) {
+            return (
+                StatusCode::NOT_MODIFIED,
+                [(header::ETAG, etag)],
+                [(header::CACHE_CONTROL, cache_control(immutable))],
+            )
+                .into_response();
+        }
+
+        let accept_encoding = headers
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok());
+        let coding = ContentCoding::negotiate(accept_encoding);
+        let (coding, body) = match precompressed(path, coding) {
+            Some(compressed) => (coding, compressed),
+            None => (ContentCoding::Identity, file.clone()),
+        };
+
+        let mut response = (
+            [
+                (header::CONTENT_TYPE, file.metadata.mimetype().to_owned()),
+                (header::ETAG, etag),
+                (header::VARY, "accept-encoding".to_owned()),
+            ],
+            body.data,
+        )
+            .into_response();
+        let response_headers = response.headers_mut();
+        response_headers.insert(header::CACHE_CONTROL, cache_control(immutable));
+        if let Some(content_encoding) = coding.header() {
+            response_headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(content_encoding),
+            );
+        }
+        response
+    }
+
+    /// The `.br` or `.gz` build of the file, if one was embedded.
+    fn precompressed(path: &str, coding: ContentCoding) -> Option<EmbeddedFile> {
+        let extension = match coding {
+            ContentCoding::Brotli => "br",
+            ContentCoding::Gzip => "gz",
+            ContentCoding::Identity => return None,
+        };
+        Assets::get(&format!("{}.{}", path, extension))
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
@@ -0,0 +1,514 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    net::IpAddr,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    broadcast::millis,
+    client::Client,
+    config::AuditConfig,
+    grid::{Canvas, Rect},
+    state::AppState,
+};
+
+/// How a write reached the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Http,
+    Ws,
+    Admin,
+}
+
+/// One changed pixel, a line of the audit log.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Entry {
+    /// Unix time in milliseconds
+    pub at: u64,
+    /// Run of the server the sequence number belongs to
+    pub epoch: u64,
+    pub seq: u64,
+    pub x: usize,
+    pub y: usize,
+    pub value: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<u64>,
+    pub transport: Transport,
+}
+
+/// Which entries to look at. Unset parts match everything, `x` and `y`
+/// alone pick a single pixel.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Filter {
+    pub x: Option<usize>,
+    pub y: Option<usize>,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    /// Unix time in milliseconds, inclusive
+    pub since: Option<u64>,
+    /// Unix time in milliseconds, exclusive
+    pub until: Option<u64>,
+    pub ip: Option<IpAddr>,
+    /// Session numbers start over with every run, so they come with `epoch`
+    pub session: Option<u64>,
+    pub epoch: Option<u64>,
+}
+
+impl Filter {
+    /// Refuses a session without the run it belongs to.
+    pub fn check(&self) -> Result<(), String> {
+        if self.session.is_some() && self.epoch.is_none() {
+            return Err("A session needs the epoch of its run".to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn area(&self) -> Option<Rect> {
+        Some(Rect {
+            x: self.x?,
+            y: self.y?,
+            width: self.width.unwrap_or(1),
+            height: self.height.unwrap_or(1),
+        })
+    }
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        let inside = self.area().is_none_or(|area| {
+            (area.x..area.x.saturating_add(area.width)).contains(&entry.x)
+                && (area.y..area.y.saturating_add(area.height)).contains(&entry.y)
+        });
+        inside
+            && self.since.is_none_or(|since| entry.at >= since)
+            && self.until.is_none_or(|until| entry.at < until)
+            && self.ip.is_none_or(|ip| entry.ip == Some(ip))
+            && self
+                .session
+                .is_none_or(|session| entry.session == Some(session))
+            && self.epoch.is_none_or(|epoch| entry.epoch == epoch)
+    }
+}
+
+impl AuditConfig {
+    /// Every file of the log, oldest first.
+    pub fn files(&self) -> Vec<String> {
+        let mut files: Vec<String> = (1..=self.keep)
+            .rev()
+            .map(|n| format!("{}.{}", self.path, n))
+            .collect();
+        files.push(self.path.clone());
+        files
+    }
+
+    /// Whether the oldest entries may have been rotated away.
+    pub fn truncated(&self) -> bool {
+        let oldest = match self.keep {
+            0 => return true,
+            keep => format!("{}.{}", self.path, keep),
+        };
+        Path::new(&oldest).exists()
+    }
+}
+
+/// Appends entries to the log file, moving it aside once it gets too big.
+/// Rotated files get a `.1`, `.2`, ... suffix, `.1` being the newest.
+/// Entries are buffered until [`AuditLog::flush`].
+pub struct AuditLog {
+    config: AuditConfig,
+    file: Option<BufWriter<File>>,
+    size: u64,
+}
+
+impl AuditLog {
+    pub fn new(config: AuditConfig) -> Self {
+        Self {
+            config,
+            file: None,
+            size: 0,
+        }
+    }
+
+    pub fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        let mut lines = vec![];
+        for entry in entries {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        let len = lines.len() as u64;
+        if self.file.is_none() {
+            self.open()?;
+        }
+        if self.size > 0 && self.size + len > self.config.max_size {
+            self.rotate()?;
+            self.open()?;
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(&lines)?;
+            self.size += len;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)?;
+        self.size = file.metadata()?.len();
+        self.file = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.file = None;
+        let path = &self.config.path;
+        let rotated = |n: usize| format!("{}.{}", path, n);
+        let moved = match self.config.keep {
+            0 => fs::remove_file(path),
+            keep => {
+                for n in (1..keep).rev() {
+                    ignore_missing(fs::rename(rotated(n), rotated(n + 1)))?;
+                }
+                fs::rename(path, rotated(1))
+            }
+        };
+        log::info!("Rotated audit log {}", path);
+        ignore_missing(moved)
+    }
+}
+
+enum Message {
+    Append(Vec<Entry>),
+    Sync(oneshot::Sender<()>),
+}
+
+/// Hands entries to a thread of its own that writes them, so a slow disk
+/// doesn't hold up writes to the board. Whatever queues up while it is busy
+/// goes to disk in one go.
+#[derive(Clone)]
+pub struct AuditWriter {
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+impl AuditWriter {
+    pub fn spawn(config: AuditConfig) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut log = AuditLog::new(config);
+        std::thread::Builder::new()
+            .name("audit".to_owned())
+            .spawn(move || {
+                let mut synced = vec![];
+                let mut next = receiver.blocking_recv();
+                while let Some(message) = next {
+                    match message {
+                        Message::Append(entries) => {
+                            if let Err(err) = log.append(&entries) {
+                                log::error!("Failed to write the audit log: {}", err);
+                            }
+                        }
+                        Message::Sync(done) => synced.push(done),
+                    }
+                    next = match receiver.try_recv() {
+                        Ok(message) => Some(message),
+                        Err(_) => {
+                            if let Err(err) = log.flush() {
+                                log::error!("Failed to write the audit log: {}", err);
+                            }
+                            for done in synced.drain(..) {
+                                let _ = done.send(());
+                            }
+                            receiver.blocking_recv()
+                        }
+                    };
+                }
+            })
+            .expect("Failed to start the audit log writer");
+        Self { sender }
+    }
+
+    pub fn append(&self, entries: Vec<Entry>) {
+        let _ = self.sender.send(Message::Append(entries));
+    }
+
+    /// Waits until every entry handed over so far is on disk.
+    pub async fn sync(&self) {
+        let (done, synced) = oneshot::channel();
+        if self.sender.send(Message::Sync(done)).is_ok() {
+            let _ = synced.await;
+        }
+    }
+}
+
+fn ignore_missing(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Calls `each` with every entry in `files`, oldest first. Lines that don't
+/// parse, like one cut short by a crash, are skipped.
+pub fn scan(files: &[String], mut each: impl FnMut(Entry)) -> io::Result<()> {
+    for path in files {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(entry) => each(entry),
+                Err(err) => log::debug!("Skipping audit line in {}: {}", path, err),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Epoch of the newest entry in `files`, found from the end of the newest
+/// file that has one rather than by reading the whole log.
+pub fn last_epoch(files: &[String]) -> io::Result<Option<u64>> {
+    // Plenty for the last few lines
+    const TAIL: u64 = 4096;
+    for path in files.iter().rev() {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(len.saturating_sub(TAIL)))?;
+        let mut tail = vec![];
+        file.read_to_end(&mut tail)?;
+        let last = tail
+            .split(|&byte| byte == b'\n')
+            .rev()
+            .find_map(|line| serde_json::from_slice::<Entry>(line).ok());
+        if let Some(entry) = last {
+            return Ok(Some(entry.epoch));
+        }
+    }
+    Ok(None)
+}
+
+/// Epoch for a new run, the current Unix time in seconds unless that would
+/// not come after the `last` one logged.
+pub fn epoch_after(last: Option<u64>) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+    last.map_or(now, |last| now.max(last + 1))
+}
+
+/// The last `limit` entries matching `filter`.
+pub fn query(files: &[String], filter: &Filter, limit: usize) -> io::Result<Vec<Entry>> {
+    let mut found = VecDeque::with_capacity(limit.min(4096));
+    scan(files, |entry| {
+        if limit > 0 && filter.matches(&entry) {
+            if found.len() == limit {
+                found.pop_front();
+            }
+            found.push_back(entry);
+        }
+    })?;
+    Ok(found.into())
+}
+
+/// Log lines for changes made under one sequence number.
+pub fn entries(
+    changes: &[(usize, bool)],
+    canvas: Canvas,
+    epoch: u64,
+    seq: u64,
+    client: Option<Client>,
+) -> Vec<Entry> {
+    let at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(millis)
+        .unwrap_or_default();
+    let transport = match client {
+        Some(Client {
+            session: Some(_), ..
+        }) => Transport::Ws,
+        Some(_) => Transport::Http,
+        None => Transport::Admin,
+    };
+    changes
+        .iter()
+        .map(|&(index, value)| {
+            let (x, y) = canvas.position(index);
+            Entry {
+                at,
+                epoch,
+                seq,
+                x,
+                y,
+                value,
+                ip: client.map(|client| client.ip),
+                session: client.and_then(|client| client.session),
+                transport,
+            }
+        })
+        .collect()
+}
+
+impl AppState {
+    /// The last `limit` logged changes matching `filter`.
+    pub async fn audit_entries(&self, filter: Filter, limit: usize) -> io::Result<Vec<Entry>> {
+        self.audit.sync().await;
+        let files = self.config.audit.files();
+        tokio::task::spawn_blocking(move || query(&files, &filter, limit))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(at: u64, x: usize, session: Option<u64>) -> Entry {
+        Entry {
+            at,
+            epoch: 1,
+            seq: at,
+            x,
+            y: 0,
+            value: true,
+            ip: Some(IpAddr::from([10, 0, 0, 1])),
+            session,
+            transport: if session.is_some() {
+                Transport::Ws
+            } else {
+                Transport::Http
+            },
+        }
+    }
+
+    #[test]
+    fn rotates_and_reads_oldest_first() {
+        let dir = std::env::temp_dir().join(format!("blobgrid-audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.ndjson").to_string_lossy().into_owned();
+        let line = serde_json::to_vec(&entry(0, 0, None)).unwrap().len() as u64 + 1;
+        let config = AuditConfig {
+            path: path.clone(),
+            // Room for two entries per file
+            max_size: 2 * line,
+            keep: 1,
+        };
+        let mut log = AuditLog::new(config.clone());
+        for at in 0..5 {
+            log.append(&[entry(at, at as usize, None)]).unwrap();
+        }
+        log.flush().unwrap();
+        assert_eq!(vec![format!("{}.1", path), path], config.files());
+
+        let all = query(&config.files(), &Filter::default(), 10).unwrap();
+        // The oldest file was dropped
+        assert_eq!(vec![2, 3, 4], all.iter().map(|e| e.at).collect::<Vec<_>>());
+        let last = query(&config.files(), &Filter::default(), 2).unwrap();
+        assert_eq!(vec![3, 4], last.iter().map(|e| e.at).collect::<Vec<_>>());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn new_runs_come_after_the_last_logged_epoch() {
+        let dir = std::env::temp_dir().join(format!("blobgrid-epoch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.ndjson").to_string_lossy().into_owned();
+        let config = AuditConfig {
+            path: path.clone(),
+            max_size: 1,
+            keep: 1,
+        };
+        assert_eq!(None, last_epoch(&config.files()).unwrap());
+
+        // A day ahead, as if the clock went back since
+        let ahead = epoch_after(None) + 86400;
+        let mut log = AuditLog::new(config.clone());
+        log.append(&[Entry {
+            epoch: ahead,
+            ..entry(0, 0, None)
+        }])
+        .unwrap();
+        log.flush().unwrap();
+        assert_eq!(Some(ahead), last_epoch(&config.files()).unwrap());
+
+        // Rotated, and the current file cut short by a crash
+        log.append(&[entry(1, 0, None)]).unwrap();
+        log.flush().unwrap();
+        fs::write(&path, "{\"at\":2,\"epo").unwrap();
+        assert_eq!(Some(ahead), last_epoch(&config.files()).unwrap());
+        assert_eq!(ahead + 1, epoch_after(Some(ahead)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn filter_by_area_time_and_client() {
+        let filter = Filter {
+            x: Some(2),
+            y: Some(0),
+            width: Some(3),
+            ..Filter::default()
+        };
+        assert!(!filter.matches(&entry(0, 1, None)));
+        assert!(filter.matches(&entry(0, 4, None)));
+        assert!(!filter.matches(&entry(0, 5, None)));
+
+        let past_the_end = Filter {
+            x: Some(usize::MAX),
+            y: Some(0),
+            width: Some(2),
+            ..Filter::default()
+        };
+        assert!(!past_the_end.matches(&entry(0, 0, None)));
+
+        let filter = Filter {
+            since: Some(10),
+            until: Some(20),
+            session: Some(7),
+            epoch: Some(1),
+            ..Filter::default()
+        };
+        assert!(filter.check().is_ok());
+        assert!(filter.matches(&entry(10, 0, Some(7))));
+        assert!(!filter.matches(&entry(20, 0, Some(7))));
+        assert!(!filter.matches(&entry(15, 0, None)));
+
+        // The same session number in another run
+        let rerun = Entry {
+            epoch: 2,
+            ..entry(10, 0, Some(7))
+        };
+        assert!(!filter.matches(&rerun));
+        let any_run = Filter {
+            epoch: None,
+            ..filter
+        };
+        assert!(any_run.check().is_err());
+
+        let other = Filter {
+            ip: Some(IpAddr::from([10, 0, 0, 2])),
+            ..Filter::default()
+        };
+        assert!(!other.matches(&entry(0, 0, None)));
+    }
+}
@@ -17,7 +17,7 @@ pub fn set_bit(byte: u8, bit_index: usize, value: bool) -> u8 {
     if value {
         (1 << bit_index) | byte
     } else {
-        !(1 << bit_index) | byte
+        !(1 << bit_index) & byte
     }
 }
 
@@ -46,6 +46,10 @@ mod tests {
 
         let b2 = 8;
         assert_eq!(136, set_bit(b2, 7, true));
+
+        let b3 = 136;
+        assert_eq!(128, set_bit(b3, 3, false));
+        assert_eq!(136, set_bit(b3, 2, false));
     }
 
     #[test]
@@ -0,0 +1,366 @@
+use std::{sync::Arc, time::Duration};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::Serialize;
+use tokio::time::{self, Instant};
+
+use crate::{
+    config::BroadcastConfig,
+    grid::Canvas,
+    mode::BoardMode,
+    presence::Cursors,
+    state::{AppState, PointQueue},
+    write::{Applied, WriteError},
+};
+
+/// Pixels that changed between two sequence numbers, with their final values.
+#[derive(Debug, Serialize)]
+pub struct Batch {
+    pub seq: u64,
+    #[serde(skip)]
+    pub from: u64,
+    pub on: Vec<usize>,
+    pub off: Vec<usize>,
+}
+
+impl Batch {
+    pub fn from_queue(queue: &PointQueue, from: u64) -> Self {
+        let mut on: Vec<usize> = queue.on.iter().copied().collect();
+        let mut off: Vec<usize> = queue.off.iter().copied().collect();
+        on.sort_unstable();
+        off.sort_unstable();
+        Self {
+            seq: queue.seq,
+            from,
+            on,
+            off,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Snapshot {
+    seq: u64,
+    data: String,
+}
+
+#[derive(Serialize)]
+struct Hello {
+    session: u64,
+    width: usize,
+    height: usize,
+    /// Sessions open now, `online` events only follow when it changes
+    online: usize,
+}
+
+#[derive(Serialize)]
+struct Online {
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct Rejected<'a> {
+    reason: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
+    #[serde(skip_serializing_if = "<[usize]>::is_empty")]
+    conflicts: &'a [usize],
+    #[serde(skip_serializing_if = "<[usize]>::is_empty")]
+    protected: &'a [usize],
+}
+
+#[derive(Serialize)]
+struct Tagged<'a, T> {
+    #[serde(rename = "type")]
+    name: &'a str,
+    #[serde(flatten)]
+    payload: &'a T,
+}
+
+/// A message for every connected client, encoded once for all transports.
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub name: &'static str,
+    pub id: Option<u64>,
+    pub data: Arc<str>,
+}
+
+impl Event {
+    fn encode<T: Serialize>(name: &'static str, id: Option<u64>, payload: &T) -> Self {
+        let data = serde_json::to_string(&Tagged { name, payload })
+            .expect("Broadcast payloads are always serializable");
+        Self {
+            name,
+            id,
+            data: data.into(),
+        }
+    }
+
+    pub fn batch(batch: &Batch) -> Self {
+        Self::encode("batch", Some(batch.seq), batch)
+    }
+
+    pub fn snapshot(seq: u64, board: &[u8]) -> Self {
+        let snapshot = Snapshot {
+            seq,
+            data: BASE64_STANDARD.encode(board),
+        };
+        Self::encode("snapshot", Some(seq), &snapshot)
+    }
+
+    pub fn hello(session: u64, canvas: Canvas, online: usize) -> Self {
+        let hello = Hello {
+            session,
+            width: canvas.width,
+            height: canvas.height,
+            online,
+        };
+        Self::encode("hello", None, &hello)
+    }
+
+    pub fn online(count: usize) -> Self {
+        Self::encode("online", None, &Online { count })
+    }
+
+    pub fn cursors(cursors: &Cursors) -> Self {
+        Self::encode("cursors", None, cursors)
+    }
+
+    pub fn rejected(err: &WriteError) -> Self {
+        let rejected = Rejected {
+            reason: err.reason(),
+            retry_after_ms: err.retry_after().map(millis),
+            conflicts: err.conflicts(),
+            protected: err.protected(),
+        };
+        Self::encode("rejected", None, &rejected)
+    }
+
+    pub fn mode(mode: &BoardMode) -> Self {
+        Self::encode("mode", None, mode)
+    }
+
+    pub fn committed(applied: &Applied) -> Self {
+        Self::encode("committed", None, applied)
+    }
+}
+
+pub fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Decides when the pending batch should go out.
+///
+/// In fixed mode every non-empty batch is flushed once per interval. In
+/// adaptive mode the delay starts at `min_interval`, doubles up to `interval`
+/// while batches are heavy or clients lag behind, and shrinks back once
+/// traffic calms down. A batch that reaches `flush_threshold` is sent early.
+pub struct Pacer {
+    config: BroadcastConfig,
+    delay: Duration,
+    last_flush: Instant,
+}
+
+impl Pacer {
+    pub fn new(config: BroadcastConfig) -> Self {
+        let delay = if config.adaptive {
+            config.min_interval.min(config.interval)
+        } else {
+            config.interval
+        };
+        Self {
+            config,
+            delay,
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Switches to new settings, keeping the current delay where it fits.
+    pub fn reconfigure(&mut self, config: BroadcastConfig) {
+        self.config = config;
+        self.delay = if self.config.adaptive {
+            self.delay.clamp(self.tick_period(), self.config.interval)
+        } else {
+            self.config.interval
+        };
+    }
+
+    pub fn tick_period(&self) -> Duration {
+        if self.config.adaptive {
+            self.config.min_interval.min(self.config.interval)
+        } else {
+            self.config.interval
+        }
+    }
+
+    pub fn should_flush(&self, pending: usize, now: Instant) -> bool {
+        if pending == 0 {
+            return false;
+        }
+        if !self.config.adaptive {
+            return true;
+        }
+        pending >= self.config.flush_threshold || now - self.last_flush >= self.delay
+    }
+
+    pub fn flushed(&mut self, pending: usize, congested: bool, now: Instant) {
+        self.last_flush = now;
+        if !self.config.adaptive {
+            return;
+        }
+        let min = self.tick_period();
+        let heavy = pending * 2 >= self.config.flush_threshold;
+        self.delay = if heavy || congested {
+            (self.delay * 2).min(self.config.interval)
+        } else {
+            (self.delay / 2).max(min)
+        };
+    }
+}
+
+pub async fn broadcast_timer(state: AppState, config: BroadcastConfig) {
+    let channel_size = config.channel_size;
+    let mut pacer = Pacer::new(config);
+    let mut interval = time::interval(pacer.tick_period());
+    let mut live = state.live.subscribe();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            Ok(()) = live.changed() => {
+                pacer.reconfigure(live.borrow_and_update().broadcast.clone());
+                let period = pacer.tick_period();
+                interval = time::interval_at(Instant::now() + period, period);
+                continue;
+            }
+        }
+        let mut points = state.queue.lock().await;
+
+        if points.is_empty() {
+            continue;
+        }
+
+        let pending = points.len();
+        let now = Instant::now();
+        if !pacer.should_flush(pending, now) {
+            continue;
+        }
+
+        let batch = {
+            let mut history = state.history.write().await;
+            let batch = Arc::new(Batch::from_queue(&points, history.last_seq()));
+            history.push(batch.clone());
+            batch
+        };
+        points.clear();
+        drop(points);
+
+        let tx = state.broadcast.lock().await;
+        if let Err(err) = tx.send(Event::batch(&batch)) {
+            log::warn!("Failed to broadcast a message, {}", err);
+        }
+        pacer.flushed(pending, tx.len() * 2 > channel_size, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_event_keeps_point_lists() {
+        let mut queue = PointQueue::new();
+        queue.on.insert(7);
+        queue.off.insert(3);
+        queue.seq = 12;
+
+        let event = Event::batch(&Batch::from_queue(&queue, 4));
+        assert_eq!(Some(12), event.id);
+        assert_eq!(
+            r#"{"type":"batch","seq":12,"on":[7],"off":[3]}"#,
+            &*event.data
+        );
+    }
+
+    #[test]
+    fn hello_carries_the_online_count() {
+        let canvas = Canvas {
+            width: 800,
+            height: 600,
+        };
+        assert_eq!(
+            r#"{"type":"hello","session":3,"width":800,"height":600,"online":2}"#,
+            &*Event::hello(3, canvas, 2).data
+        );
+    }
+
+    fn adaptive() -> BroadcastConfig {
+        BroadcastConfig {
+            interval: Duration::from_millis(1600),
+            channel_size: 100,
+            adaptive: true,
+            min_interval: Duration::from_millis(100),
+            flush_threshold: 100,
+            history_size: 0,
+        }
+    }
+
+    #[test]
+    fn fixed_flushes_every_tick() {
+        let pacer = Pacer::new(BroadcastConfig::default());
+        let now = Instant::now();
+        assert_eq!(Duration::from_millis(5000), pacer.tick_period());
+        assert!(!pacer.should_flush(0, now));
+        assert!(pacer.should_flush(1, now));
+    }
+
+    #[test]
+    fn adaptive_flushes_early_on_threshold() {
+        let pacer = Pacer::new(adaptive());
+        let start = pacer.last_flush;
+        assert!(!pacer.should_flush(10, start));
+        assert!(pacer.should_flush(100, start));
+        assert!(pacer.should_flush(10, start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn adaptive_backs_off_and_recovers() {
+        let mut pacer = Pacer::new(adaptive());
+        let now = Instant::now();
+        for _ in 0..10 {
+            pacer.flushed(60, false, now);
+        }
+        assert_eq!(Duration::from_millis(1600), pacer.delay);
+
+        pacer.flushed(1, false, now);
+        assert_eq!(Duration::from_millis(800), pacer.delay);
+
+        pacer.flushed(1, true, now);
+        assert_eq!(Duration::from_millis(1600), pacer.delay);
+
+        for _ in 0..10 {
+            pacer.flushed(1, false, now);
+        }
+        assert_eq!(Duration::from_millis(100), pacer.delay);
+    }
+
+    #[test]
+    fn reconfigure_keeps_delay_in_new_bounds() {
+        let mut pacer = Pacer::new(adaptive());
+        pacer.flushed(60, false, Instant::now());
+        assert_eq!(Duration::from_millis(200), pacer.delay);
+
+        pacer.reconfigure(BroadcastConfig {
+            min_interval: Duration::from_millis(500),
+            ..adaptive()
+        });
+        assert_eq!(Duration::from_millis(500), pacer.delay);
+
+        pacer.reconfigure(BroadcastConfig {
+            interval: Duration::from_millis(300),
+            adaptive: false,
+            ..adaptive()
+        });
+        assert_eq!(Duration::from_millis(300), pacer.tick_period());
+        assert!(pacer.should_flush(1, pacer.last_flush));
+    }
+}
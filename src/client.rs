@@ -0,0 +1,153 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap, StatusCode},
+};
+use ipnet::IpNet;
+
+use crate::state::AppState;
+
+/// Who is writing to the board.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Client {
+    pub ip: IpAddr,
+    /// Websocket session, if the write came over one
+    pub session: Option<u64>,
+}
+
+impl Client {
+    pub fn http(ip: IpAddr) -> Self {
+        Self { ip, session: None }
+    }
+
+    pub fn ws(ip: IpAddr, session: u64) -> Self {
+        Self {
+            ip,
+            session: Some(session),
+        }
+    }
+}
+
+/// Resolves the real client address, trusting proxy headers only from
+/// configured proxies.
+#[async_trait]
+impl FromRequestParts<AppState> for Client {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .copied()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let ip = client_ip(peer.ip(), &parts.headers, &state.config.trusted_proxies);
+        Ok(Client::http(ip))
+    }
+}
+
+fn is_trusted(ip: IpAddr, trusted: &[IpNet]) -> bool {
+    trusted.iter().any(|net| net.contains(&ip))
+}
+
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    if !is_trusted(peer, trusted) {
+        return peer;
+    }
+
+    // Walk the chain from the nearest hop, the first untrusted one is the
+    // client. Hops left of one that doesn't parse can't be vouched for, so
+    // the last trusted address seen stands in for the client.
+    let hops: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    if !hops.is_empty() {
+        let mut nearest = peer;
+        for hop in hops.iter().rev() {
+            match hop.trim().parse() {
+                Ok(ip) if is_trusted(ip, trusted) => nearest = ip,
+                Ok(ip) => return ip,
+                Err(_) => break,
+            }
+        }
+        return nearest;
+    }
+
+    headers
+        .get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let trusted: Vec<IpNet> = vec!["127.0.0.1/32".parse().unwrap()];
+        let peer: IpAddr = "203.0.113.9".parse().unwrap();
+        let spoofed = headers(&[("x-real-ip", "10.1.1.1"), ("x-forwarded-for", "10.1.1.1")]);
+        assert_eq!(peer, client_ip(peer, &spoofed, &trusted));
+    }
+
+    #[test]
+    fn trusted_peer_forwards_the_client() {
+        let trusted: Vec<IpNet> = vec![
+            "127.0.0.1/32".parse().unwrap(),
+            "10.0.0.0/8".parse().unwrap(),
+        ];
+        let peer: IpAddr = "127.0.0.1".parse().unwrap();
+
+        let real_ip = headers(&[("x-real-ip", "198.51.100.7")]);
+        assert_eq!(
+            "198.51.100.7".parse::<IpAddr>().unwrap(),
+            client_ip(peer, &real_ip, &trusted)
+        );
+
+        let chain = headers(&[("x-forwarded-for", "1.2.3.4, 198.51.100.7, 10.0.0.5")]);
+        assert_eq!(
+            "198.51.100.7".parse::<IpAddr>().unwrap(),
+            client_ip(peer, &chain, &trusted)
+        );
+
+        assert_eq!(peer, client_ip(peer, &HeaderMap::new(), &trusted));
+    }
+
+    #[test]
+    fn unparsable_hop_ends_the_chain() {
+        let trusted: Vec<IpNet> = vec![
+            "127.0.0.1/32".parse().unwrap(),
+            "10.0.0.0/8".parse().unwrap(),
+        ];
+        let peer: IpAddr = "127.0.0.1".parse().unwrap();
+
+        let spoofed = headers(&[("x-forwarded-for", "1.2.3.4, garbage")]);
+        assert_eq!(peer, client_ip(peer, &spoofed, &trusted));
+
+        let garbage = headers(&[("x-forwarded-for", "garbage"), ("x-real-ip", "1.2.3.4")]);
+        assert_eq!(peer, client_ip(peer, &garbage, &trusted));
+
+        let behind = headers(&[("x-forwarded-for", "1.2.3.4, garbage, 10.0.0.5")]);
+        assert_eq!(
+            "10.0.0.5".parse::<IpAddr>().unwrap(),
+            client_ip(peer, &behind, &trusted)
+        );
+    }
+}
@@ -1,14 +1,1077 @@
-use clap::Parser;
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::{grid::Canvas, mode::Mode, write::PIXELS};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
-    #[arg(short, long)]
+    /// TOML file with settings, overridden by BLOBGRID_* variables and flags
+    #[arg(short, long, env = "BLOBGRID_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Print the effective settings as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    #[command(flatten)]
+    pub settings: Settings,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Roll the dump back to an earlier point using the audit log. Run it
+    /// while the server is stopped, or use the admin API on a running one.
+    Rollback(RollbackArgs),
+}
+
+#[derive(Args)]
+#[group(skip)]
+#[command(group(ArgGroup::new("target").required(true).args(["at", "seq"])))]
+pub struct RollbackArgs {
+    /// Unix time in milliseconds to go back to
+    #[arg(long)]
+    pub at: Option<u64>,
+
+    /// Sequence number to go back to
+    #[arg(long)]
+    pub seq: Option<u64>,
+
+    /// Run the sequence number belongs to, the last logged one by default
+    #[arg(long, conflicts_with = "at")]
+    pub epoch: Option<u64>,
+
+    /// Only write the rolled back board to this PNG, leaving the dump alone
+    #[arg(long, value_name = "PNG")]
+    pub preview: Option<String>,
+}
+
+impl Cli {
+    /// Settings from every layer, with the config file read afresh.
+    pub fn load(&self) -> Result<Config, ConfigError> {
+        let file = match &self.config {
+            Some(path) => Settings::read(path)?,
+            None => Settings::default(),
+        };
+        self.settings.clone().layered(file).resolve()
+    }
+}
+
+/// Every tunable of the server. Flags win over `BLOBGRID_*` variables,
+/// which win over the config file, which wins over the defaults. Keys in
+/// the file are the flag names with underscores.
+#[derive(Args, Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[command(about = None, long_about = None)]
+pub struct Settings {
+    /// Port to listen on, 3000 by default
+    #[arg(short, long, env = "BLOBGRID_PORT")]
     pub port: Option<u16>,
 
-    #[arg(short, long)]
+    /// Address to listen on, 127.0.0.1 by default, :: or 0.0.0.0 for all
+    #[arg(long, env = "BLOBGRID_BIND")]
+    pub bind: Option<IpAddr>,
+
+    /// Listen on a Unix socket instead of TCP
+    #[arg(long, env = "BLOBGRID_UNIX_SOCKET")]
+    pub unix_socket: Option<PathBuf>,
+
+    /// PEM certificate chain, enables HTTPS, reloaded on SIGHUP
+    #[arg(long, env = "BLOBGRID_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for the certificate
+    #[arg(long, env = "BLOBGRID_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Board dump file, dump.bin by default
+    #[arg(short, long, env = "BLOBGRID_DUMP_PATH")]
     pub dump_path: Option<String>,
 
-    #[arg(short, long)]
-    pub bitmap_path: Option<String>
+    /// PNG snapshot file, dump.png by default
+    #[arg(short, long, env = "BLOBGRID_BITMAP_PATH")]
+    pub bitmap_path: Option<String>,
+
+    /// Protected regions file, regions.json next to the dump by default, read
+    /// again on SIGHUP
+    #[arg(long, env = "BLOBGRID_REGIONS_PATH")]
+    pub regions_path: Option<String>,
+
+    /// NDJSON log of every pixel change, audit.ndjson by default
+    #[arg(long, env = "BLOBGRID_AUDIT_PATH")]
+    pub audit_path: Option<String>,
+
+    /// Megabytes the audit log grows to before it is rotated, 100 by default
+    #[arg(long, env = "BLOBGRID_AUDIT_MAX_SIZE")]
+    pub audit_max_size: Option<u64>,
+
+    /// Rotated audit logs to keep, 5 by default
+    #[arg(long, env = "BLOBGRID_AUDIT_KEEP")]
+    pub audit_keep: Option<usize>,
+
+    /// Seconds between saves of the dump and the PNG snapshot
+    #[arg(long, env = "BLOBGRID_SAVE_INTERVAL")]
+    pub save_interval: Option<u64>,
+
+    /// Canvas width in pixels, 1000 by default
+    #[arg(long, env = "BLOBGRID_WIDTH")]
+    pub width: Option<usize>,
+
+    /// Canvas height in pixels, 1000 by default
+    #[arg(long, env = "BLOBGRID_HEIGHT")]
+    pub height: Option<usize>,
+
+    /// Colour of set pixels in PNG output, #ff0000 by default
+    #[arg(long, env = "BLOBGRID_FILLED_COLOR")]
+    pub filled_color: Option<Color>,
+
+    /// Colour of clear pixels in PNG output, #ffffff by default
+    #[arg(long, env = "BLOBGRID_EMPTY_COLOR")]
+    pub empty_color: Option<Color>,
+
+    /// Serve the frontend from this directory, usually frontend/build
+    #[arg(long, env = "BLOBGRID_STATIC_DIR")]
+    pub static_dir: Option<PathBuf>,
+
+    /// Broadcast interval in milliseconds, upper bound in adaptive mode
+    #[arg(long, env = "BLOBGRID_BROADCAST_INTERVAL")]
+    pub broadcast_interval: Option<u64>,
+
+    /// Number of batches a slow client may lag behind before losing them
+    #[arg(long, env = "BLOBGRID_BROADCAST_CHANNEL_SIZE")]
+    pub broadcast_channel_size: Option<usize>,
+
+    /// Flush small batches quickly and back off under load
+    #[arg(long, env = "BLOBGRID_ADAPTIVE_BROADCAST", num_args = 0..=1, default_missing_value = "true")]
+    pub adaptive_broadcast: Option<bool>,
+
+    /// Shortest broadcast interval in milliseconds for adaptive mode
+    #[arg(long, env = "BLOBGRID_BROADCAST_MIN_INTERVAL")]
+    pub broadcast_min_interval: Option<u64>,
+
+    /// Batch size that triggers an early flush in adaptive mode
+    #[arg(long, env = "BLOBGRID_BROADCAST_FLUSH_THRESHOLD")]
+    pub broadcast_flush_threshold: Option<usize>,
+
+    /// Number of broadcast batches kept for clients catching up
+    #[arg(long, env = "BLOBGRID_HISTORY_SIZE")]
+    pub history_size: Option<usize>,
+
+    /// How often to check and announce the online count, in milliseconds
+    #[arg(long, env = "BLOBGRID_PRESENCE_INTERVAL")]
+    pub presence_interval: Option<u64>,
+
+    /// Relay other users' cursor positions to websocket clients
+    #[arg(long, env = "BLOBGRID_CURSORS", num_args = 0..=1, default_missing_value = "true")]
+    pub cursors: Option<bool>,
+
+    /// Minimum time between cursor updates in milliseconds
+    #[arg(long, env = "BLOBGRID_CURSOR_INTERVAL")]
+    pub cursor_interval: Option<u64>,
+
+    /// Seconds between server pings on websockets
+    #[arg(long, env = "BLOBGRID_PING_INTERVAL")]
+    pub ping_interval: Option<u64>,
+
+    /// Seconds to wait for a pong before dropping the websocket
+    #[arg(long, env = "BLOBGRID_PONG_TIMEOUT")]
+    pub pong_timeout: Option<u64>,
+
+    /// Close websockets that sent nothing but pongs for this many seconds
+    #[arg(long, env = "BLOBGRID_IDLE_TIMEOUT")]
+    pub idle_timeout: Option<u64>,
+
+    /// Close websockets older than this many seconds
+    #[arg(long, env = "BLOBGRID_MAX_SESSION")]
+    pub max_session: Option<u64>,
+
+    /// Maximum number of websocket sessions
+    #[arg(long, env = "BLOBGRID_MAX_SESSIONS")]
+    pub max_sessions: Option<usize>,
+
+    /// Maximum number of websocket sessions from one client address
+    #[arg(long, env = "BLOBGRID_MAX_SESSIONS_PER_IP")]
+    pub max_sessions_per_ip: Option<usize>,
+
+    /// Maximum number of new websockets per second from one client address
+    #[arg(long, env = "BLOBGRID_MAX_CONNECTS_PER_SEC")]
+    pub max_connects_per_sec: Option<u32>,
+
+    /// Pixel writes a client may make in a burst, unlimited when unset
+    #[arg(long, env = "BLOBGRID_WRITE_BURST")]
+    pub write_burst: Option<u32>,
+
+    /// Pixel writes per second returned to each client's allowance
+    #[arg(long, env = "BLOBGRID_WRITE_REFILL")]
+    pub write_refill: Option<f64>,
+
+    /// Proxy network whose X-Forwarded-For and X-Real-IP headers are
+    /// trusted, may be repeated
+    #[arg(
+        long = "trusted-proxy",
+        value_name = "CIDR",
+        env = "BLOBGRID_TRUSTED_PROXIES",
+        value_delimiter = ','
+    )]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Client network not allowed to write or open websockets, may be
+    /// repeated
+    #[arg(
+        long = "ban",
+        value_name = "CIDR",
+        env = "BLOBGRID_BANNED",
+        value_delimiter = ','
+    )]
+    pub banned: Vec<IpNet>,
+
+    /// Most pixels accepted in one batch write
+    #[arg(long, env = "BLOBGRID_MAX_BATCH")]
+    pub max_batch: Option<usize>,
+
+    /// Bearer token for the /admin API, which is off when unset
+    #[arg(long, env = "BLOBGRID_ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Board mode at startup, open by default, the admin API switches it
+    #[arg(long, env = "BLOBGRID_MODE")]
+    pub mode: Option<Mode>,
+
+    /// Message for clients while the board is read-only or in maintenance
+    #[arg(long, env = "BLOBGRID_NOTICE")]
+    pub notice: Option<String>,
+}
+
+impl Settings {
+    pub fn read(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|err| ConfigError::Read(path.into(), err))?;
+        toml::from_str(&text).map_err(|err| ConfigError::Parse(path.into(), err))
+    }
+
+    /// These settings with the gaps filled in from `lower`.
+    pub fn layered(self, lower: Settings) -> Settings {
+        Settings {
+            port: self.port.or(lower.port),
+            bind: self.bind.or(lower.bind),
+            unix_socket: self.unix_socket.or(lower.unix_socket),
+            tls_cert: self.tls_cert.or(lower.tls_cert),
+            tls_key: self.tls_key.or(lower.tls_key),
+            dump_path: self.dump_path.or(lower.dump_path),
+            bitmap_path: self.bitmap_path.or(lower.bitmap_path),
+            regions_path: self.regions_path.or(lower.regions_path),
+            audit_path: self.audit_path.or(lower.audit_path),
+            audit_max_size: self.audit_max_size.or(lower.audit_max_size),
+            audit_keep: self.audit_keep.or(lower.audit_keep),
+            save_interval: self.save_interval.or(lower.save_interval),
+            width: self.width.or(lower.width),
+            height: self.height.or(lower.height),
+            filled_color: self.filled_color.or(lower.filled_color),
+            empty_color: self.empty_color.or(lower.empty_color),
+            static_dir: self.static_dir.or(lower.static_dir),
+            broadcast_interval: self.broadcast_interval.or(lower.broadcast_interval),
+            broadcast_channel_size: self.broadcast_channel_size.or(lower.broadcast_channel_size),
+            adaptive_broadcast: self.adaptive_broadcast.or(lower.adaptive_broadcast),
+            broadcast_min_interval: self.broadcast_min_interval.or(lower.broadcast_min_interval),
+            broadcast_flush_threshold: self
+                .broadcast_flush_threshold
+                .or(lower.broadcast_flush_threshold),
+            history_size: self.history_size.or(lower.history_size),
+            presence_interval: self.presence_interval.or(lower.presence_interval),
+            cursors: self.cursors.or(lower.cursors),
+            cursor_interval: self.cursor_interval.or(lower.cursor_interval),
+            ping_interval: self.ping_interval.or(lower.ping_interval),
+            pong_timeout: self.pong_timeout.or(lower.pong_timeout),
+            idle_timeout: self.idle_timeout.or(lower.idle_timeout),
+            max_session: self.max_session.or(lower.max_session),
+            max_sessions: self.max_sessions.or(lower.max_sessions),
+            max_sessions_per_ip: self.max_sessions_per_ip.or(lower.max_sessions_per_ip),
+            max_connects_per_sec: self.max_connects_per_sec.or(lower.max_connects_per_sec),
+            write_burst: self.write_burst.or(lower.write_burst),
+            write_refill: self.write_refill.or(lower.write_refill),
+            trusted_proxies: if self.trusted_proxies.is_empty() {
+                lower.trusted_proxies
+            } else {
+                self.trusted_proxies
+            },
+            banned: if self.banned.is_empty() {
+                lower.banned
+            } else {
+                self.banned
+            },
+            max_batch: self.max_batch.or(lower.max_batch),
+            admin_token: self.admin_token.or(lower.admin_token),
+            mode: self.mode.or(lower.mode),
+            notice: self.notice.or(lower.notice),
+        }
+    }
+
+    /// Fills in the defaults and checks that the result makes sense.
+    pub fn resolve(self) -> Result<Config, ConfigError> {
+        let default = Config::default();
+        let (listen, broadcast) = (self.listen_config()?, self.broadcast_config());
+        let (presence, heartbeat) = (self.presence_config(), self.heartbeat_config());
+        let dump_path = self.dump_path.unwrap_or(default.storage.dump_path);
+        let regions_path = self.regions_path.unwrap_or_else(|| {
+            Path::new(&dump_path)
+                .with_file_name(&default.storage.regions_path)
+                .to_string_lossy()
+                .into_owned()
+        });
+        let config = Config {
+            listen,
+            storage: StorageConfig {
+                dump_path,
+                bitmap_path: self.bitmap_path.unwrap_or(default.storage.bitmap_path),
+                regions_path,
+                save_interval: self
+                    .save_interval
+                    .map(Duration::from_secs)
+                    .unwrap_or(default.storage.save_interval),
+            },
+            audit: AuditConfig {
+                path: self.audit_path.unwrap_or(default.audit.path),
+                max_size: self
+                    .audit_max_size
+                    .map(|megabytes| megabytes.saturating_mul(MEGABYTE))
+                    .unwrap_or(default.audit.max_size),
+                keep: self.audit_keep.unwrap_or(default.audit.keep),
+            },
+            canvas: Canvas {
+                width: self.width.unwrap_or(default.canvas.width),
+                height: self.height.unwrap_or(default.canvas.height),
+            },
+            colors: Colors {
+                filled: self.filled_color.unwrap_or(default.colors.filled),
+                empty: self.empty_color.unwrap_or(default.colors.empty),
+            },
+            static_dir: self.static_dir,
+            broadcast,
+            presence,
+            heartbeat,
+            connections: ConnectionConfig {
+                max_sessions: self.max_sessions,
+                max_sessions_per_ip: self.max_sessions_per_ip,
+                max_connects_per_sec: self.max_connects_per_sec,
+            },
+            rate_limit: RateLimitConfig {
+                burst: self.write_burst,
+                refill_per_sec: self
+                    .write_refill
+                    .unwrap_or(default.rate_limit.refill_per_sec),
+            },
+            trusted_proxies: self.trusted_proxies,
+            banned: self.banned,
+            max_batch: self.max_batch.unwrap_or(default.max_batch),
+            admin_token: self.admin_token,
+            mode: self.mode.unwrap_or_default(),
+            notice: self.notice,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn listen_config(&self) -> Result<ListenConfig, ConfigError> {
+        let default = ListenConfig::default();
+        let tls = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig {
+                cert: cert.clone(),
+                key: key.clone(),
+            }),
+            (None, None) => None,
+            _ => return Err(invalid("tls_cert and tls_key go together")),
+        };
+        if self.unix_socket.is_some() {
+            if self.bind.is_some() || self.port.is_some() {
+                return Err(invalid("unix_socket can't be combined with bind or port"));
+            }
+            if tls.is_some() {
+                return Err(invalid("TLS isn't supported on a unix_socket"));
+            }
+            if cfg!(not(unix)) {
+                return Err(invalid("unix_socket is only supported on Unix"));
+            }
+        }
+        Ok(ListenConfig {
+            bind: self.bind.unwrap_or(default.bind),
+            port: self.port.unwrap_or(default.port),
+            unix_socket: self.unix_socket.clone(),
+            tls,
+        })
+    }
+
+    fn broadcast_config(&self) -> BroadcastConfig {
+        let default = BroadcastConfig::default();
+        BroadcastConfig {
+            interval: self
+                .broadcast_interval
+                .map(Duration::from_millis)
+                .unwrap_or(default.interval),
+            channel_size: self.broadcast_channel_size.unwrap_or(default.channel_size),
+            adaptive: self.adaptive_broadcast.unwrap_or(default.adaptive),
+            min_interval: self
+                .broadcast_min_interval
+                .map(Duration::from_millis)
+                .unwrap_or(default.min_interval),
+            flush_threshold: self
+                .broadcast_flush_threshold
+                .unwrap_or(default.flush_threshold),
+            history_size: self.history_size.unwrap_or(default.history_size),
+        }
+    }
+
+    fn presence_config(&self) -> PresenceConfig {
+        let default = PresenceConfig::default();
+        PresenceConfig {
+            online_interval: self
+                .presence_interval
+                .map(Duration::from_millis)
+                .unwrap_or(default.online_interval),
+            cursors: self.cursors.unwrap_or(default.cursors),
+            cursor_interval: self
+                .cursor_interval
+                .map(Duration::from_millis)
+                .unwrap_or(default.cursor_interval),
+        }
+    }
+
+    fn heartbeat_config(&self) -> HeartbeatConfig {
+        let default = HeartbeatConfig::default();
+        HeartbeatConfig {
+            ping_interval: self
+                .ping_interval
+                .map(Duration::from_secs)
+                .unwrap_or(default.ping_interval),
+            pong_timeout: self
+                .pong_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(default.pong_timeout),
+            idle_timeout: self.idle_timeout.map(Duration::from_secs),
+            max_session: self.max_session.map(Duration::from_secs),
+        }
+    }
+
+    /// Every setting spelled out as `--print-config` shows them, with the
+    /// admin token only said to be set so the output can be shared.
+    pub fn printable(config: &Config) -> Self {
+        let mut settings = Self::effective(config);
+        if settings.admin_token.is_some() {
+            settings.admin_token = Some("<set>".to_owned());
+        }
+        settings
+    }
+
+    /// Every setting spelled out.
+    pub fn effective(config: &Config) -> Self {
+        let secs = |duration: Duration| duration.as_secs();
+        let millis = |duration: Duration| crate::broadcast::millis(duration);
+        Settings {
+            port: config
+                .listen
+                .unix_socket
+                .is_none()
+                .then_some(config.listen.port),
+            bind: config
+                .listen
+                .unix_socket
+                .is_none()
+                .then_some(config.listen.bind),
+            unix_socket: config.listen.unix_socket.clone(),
+            tls_cert: config.listen.tls.as_ref().map(|tls| tls.cert.clone()),
+            tls_key: config.listen.tls.as_ref().map(|tls| tls.key.clone()),
+            dump_path: Some(config.storage.dump_path.clone()),
+            bitmap_path: Some(config.storage.bitmap_path.clone()),
+            regions_path: Some(config.storage.regions_path.clone()),
+            audit_path: Some(config.audit.path.clone()),
+            audit_max_size: Some(config.audit.max_size / MEGABYTE),
+            audit_keep: Some(config.audit.keep),
+            save_interval: Some(secs(config.storage.save_interval)),
+            width: Some(config.canvas.width),
+            height: Some(config.canvas.height),
+            filled_color: Some(config.colors.filled),
+            empty_color: Some(config.colors.empty),
+            static_dir: config.static_dir.clone(),
+            broadcast_interval: Some(millis(config.broadcast.interval)),
+            broadcast_channel_size: Some(config.broadcast.channel_size),
+            adaptive_broadcast: Some(config.broadcast.adaptive),
+            broadcast_min_interval: Some(millis(config.broadcast.min_interval)),
+            broadcast_flush_threshold: Some(config.broadcast.flush_threshold),
+            history_size: Some(config.broadcast.history_size),
+            presence_interval: Some(millis(config.presence.online_interval)),
+            cursors: Some(config.presence.cursors),
+            cursor_interval: Some(millis(config.presence.cursor_interval)),
+            ping_interval: Some(secs(config.heartbeat.ping_interval)),
+            pong_timeout: Some(secs(config.heartbeat.pong_timeout)),
+            idle_timeout: config.heartbeat.idle_timeout.map(secs),
+            max_session: config.heartbeat.max_session.map(secs),
+            max_sessions: config.connections.max_sessions,
+            max_sessions_per_ip: config.connections.max_sessions_per_ip,
+            max_connects_per_sec: config.connections.max_connects_per_sec,
+            write_burst: config.rate_limit.burst,
+            write_refill: Some(config.rate_limit.refill_per_sec),
+            trusted_proxies: config.trusted_proxies.clone(),
+            banned: config.banned.clone(),
+            max_batch: Some(config.max_batch),
+            admin_token: config.admin_token.clone(),
+            mode: Some(config.mode),
+            notice: config.notice.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "Can't read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "Can't parse {}: {}", path.display(), err),
+            ConfigError::Invalid(reason) => write!(f, "Invalid config: {}", reason),
+        }
+    }
+}
+
+fn invalid(reason: &str) -> ConfigError {
+    ConfigError::Invalid(reason.to_owned())
+}
+
+/// An RGB colour written as `#rrggbb`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color(pub [u8; 3]);
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        let channel = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+        };
+        match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(r), Some(g), Some(b)) => Ok(Color([r, g, b])),
+            _ => Err(format!("Expected a colour like #ff0000, got {:?}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Color> for String {
+    fn from(color: Color) -> Self {
+        color.to_string()
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [r, g, b] = self.0;
+        write!(f, "#{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+/// Tunables shared by the whole server.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub listen: ListenConfig,
+    pub storage: StorageConfig,
+    pub audit: AuditConfig,
+    pub canvas: Canvas,
+    pub colors: Colors,
+    pub broadcast: BroadcastConfig,
+    pub presence: PresenceConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub connections: ConnectionConfig,
+    pub rate_limit: RateLimitConfig,
+    pub trusted_proxies: Vec<IpNet>,
+    pub banned: Vec<IpNet>,
+    pub max_batch: usize,
+    pub admin_token: Option<String>,
+    pub mode: Mode,
+    pub notice: Option<String>,
+    /// Frontend build to serve, embedded assets or a placeholder otherwise
+    pub static_dir: Option<PathBuf>,
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let positive = [
+            ("save_interval", self.storage.save_interval),
+            ("broadcast_interval", self.broadcast.interval),
+            ("broadcast_min_interval", self.broadcast.min_interval),
+            ("presence_interval", self.presence.online_interval),
+            ("cursor_interval", self.presence.cursor_interval),
+            ("ping_interval", self.heartbeat.ping_interval),
+            ("pong_timeout", self.heartbeat.pong_timeout),
+        ];
+        for (name, duration) in positive {
+            if duration.is_zero() {
+                return Err(ConfigError::Invalid(format!("{} must be above zero", name)));
+            }
+        }
+        if self.audit.max_size == 0 {
+            return Err(invalid("audit_max_size must be at least 1"));
+        }
+        if self.broadcast.channel_size == 0 {
+            return Err(invalid("broadcast_channel_size must be at least 1"));
+        }
+        if self.broadcast.flush_threshold == 0 {
+            return Err(invalid("broadcast_flush_threshold must be at least 1"));
+        }
+        if self
+            .admin_token
+            .as_ref()
+            .is_some_and(|token| token.len() < 16)
+        {
+            return Err(invalid("admin_token must be at least 16 characters"));
+        }
+        if self.max_batch == 0 {
+            return Err(invalid("max_batch must be at least 1"));
+        }
+        if !(self.rate_limit.refill_per_sec.is_finite() && self.rate_limit.refill_per_sec > 0.0) {
+            return Err(invalid("write_refill must be a positive number"));
+        }
+        let Canvas { width, height } = self.canvas;
+        if width == 0 || height == 0 || width.saturating_mul(height) > PIXELS {
+            return Err(ConfigError::Invalid(format!(
+                "Canvas of {}x{} doesn't fit the board's {} pixels",
+                width, height, PIXELS
+            )));
+        }
+        Ok(())
+    }
+
+    /// The part of this config a reload applies.
+    pub fn live(&self) -> LiveConfig {
+        LiveConfig {
+            rate_limit: self.rate_limit.clone(),
+            broadcast: self.broadcast.clone(),
+            save_interval: self.storage.save_interval,
+            banned: self.banned.clone(),
+        }
+    }
+
+    /// This config with the settings of `live` in effect.
+    pub fn with_live(&self, live: &LiveConfig) -> Config {
+        let mut config = self.clone();
+        config.rate_limit = live.rate_limit.clone();
+        config.broadcast = BroadcastConfig {
+            // Sized once at startup
+            channel_size: self.broadcast.channel_size,
+            history_size: self.broadcast.history_size,
+            ..live.broadcast.clone()
+        };
+        config.storage.save_interval = live.save_interval;
+        config.banned = live.banned.clone();
+        config
+    }
+}
+
+/// Settings that can change while the server runs, on SIGHUP.
+#[derive(Clone, Debug)]
+pub struct LiveConfig {
+    pub rate_limit: RateLimitConfig,
+    /// Pacing only, the channel and history keep their startup sizes
+    pub broadcast: BroadcastConfig,
+    pub save_interval: Duration,
+    pub banned: Vec<IpNet>,
+}
+
+impl LiveConfig {
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Keys of the settings in [`LiveConfig`]
+const LIVE_KEYS: [&str; 8] = [
+    "write_burst",
+    "write_refill",
+    "broadcast_interval",
+    "adaptive_broadcast",
+    "broadcast_min_interval",
+    "broadcast_flush_threshold",
+    "save_interval",
+    "banned",
+];
+
+/// A setting that differs between two configs, values as written in TOML.
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl Change {
+    /// Whether a reload applies it, the rest waits for a restart.
+    pub fn is_live(&self) -> bool {
+        LIVE_KEYS.contains(&self.key.as_str())
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = |value: &Option<String>| value.clone().unwrap_or("unset".to_owned());
+        write!(
+            f,
+            "{} {} -> {}",
+            self.key,
+            value(&self.old),
+            value(&self.new)
+        )
+    }
+}
+
+/// Every setting that differs between `old` and `new`.
+pub fn changes(old: &Config, new: &Config) -> Vec<Change> {
+    let table =
+        |config: &Config| toml::Table::try_from(Settings::effective(config)).unwrap_or_default();
+    let (old, new) = (table(old), table(new));
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter(|key| old.get(*key) != new.get(*key))
+        .map(|key| {
+            let shown = |value: &toml::Value| match key.as_str() {
+                "admin_token" => "(hidden)".to_owned(),
+                _ => value.to_string(),
+            };
+            Change {
+                key: key.clone(),
+                old: old.get(key).map(shown),
+                new: new.get(key).map(shown),
+            }
+        })
+        .collect()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: ListenConfig::default(),
+            storage: StorageConfig::default(),
+            audit: AuditConfig::default(),
+            canvas: Canvas::default(),
+            colors: Colors::default(),
+            broadcast: BroadcastConfig::default(),
+            presence: PresenceConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            connections: ConnectionConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            trusted_proxies: vec![],
+            banned: vec![],
+            max_batch: 4096,
+            admin_token: None,
+            mode: Mode::Open,
+            notice: None,
+            static_dir: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Defaults with the audit log in a temporary directory, for tests that
+    /// write to the board.
+    pub fn scratch(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("blobgrid-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = Config::default();
+        config.audit.path = dir.join("audit.ndjson").to_string_lossy().into_owned();
+        config
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ListenConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub unix_socket: Option<PathBuf>,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            bind: Ipv4Addr::LOCALHOST.into(),
+            port: 3000,
+            unix_socket: None,
+            tls: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Clone, Debug)]
+pub struct StorageConfig {
+    pub dump_path: String,
+    pub bitmap_path: String,
+    pub regions_path: String,
+    pub save_interval: Duration,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            dump_path: "dump.bin".to_owned(),
+            bitmap_path: "dump.png".to_owned(),
+            regions_path: "regions.json".to_owned(),
+            save_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+const MEGABYTE: u64 = 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct AuditConfig {
+    pub path: String,
+    /// Bytes
+    pub max_size: u64,
+    pub keep: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            path: "audit.ndjson".to_owned(),
+            max_size: 100 * MEGABYTE,
+            keep: 5,
+        }
+    }
+}
+
+/// Colours of rendered PNGs.
+#[derive(Clone, Copy, Debug)]
+pub struct Colors {
+    pub filled: Color,
+    pub empty: Color,
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Self {
+            filled: Color([255, 0, 0]),
+            empty: Color([255, 255, 255]),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BroadcastConfig {
+    pub interval: Duration,
+    pub channel_size: usize,
+    pub adaptive: bool,
+    pub min_interval: Duration,
+    pub flush_threshold: usize,
+    pub history_size: usize,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(5000),
+            channel_size: 100,
+            adaptive: false,
+            min_interval: Duration::from_millis(100),
+            flush_threshold: 1000,
+            history_size: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PresenceConfig {
+    pub online_interval: Duration,
+    pub cursors: bool,
+    pub cursor_interval: Duration,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            online_interval: Duration::from_millis(2000),
+            cursors: false,
+            cursor_interval: Duration::from_millis(200),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HeartbeatConfig {
+    pub ping_interval: Duration,
+    pub pong_timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+    pub max_session: Option<Duration>,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(30),
+            pong_timeout: Duration::from_secs(10),
+            idle_timeout: None,
+            max_session: None,
+        }
+    }
+}
+
+/// Caps on websocket sessions, unlimited when unset.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionConfig {
+    pub max_sessions: Option<usize>,
+    pub max_sessions_per_ip: Option<usize>,
+    pub max_connects_per_sec: Option<u32>,
+}
+
+/// Token bucket settings for pixel writes.
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    pub burst: Option<u32>,
+    pub refill_per_sec: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            burst: None,
+            refill_per_sec: 10.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers_fill_gaps_from_below() {
+        let flags = Settings {
+            port: Some(4000),
+            ..Settings::default()
+        };
+        let file: Settings = toml::from_str(
+            r##"
+            port = 5000
+            broadcast_interval = 250
+            filled_color = "#000000"
+            trusted_proxies = ["10.0.0.0/8"]
+            "##,
+        )
+        .unwrap();
+
+        let config = flags.layered(file).resolve().unwrap();
+        assert_eq!(4000, config.listen.port);
+        assert_eq!(Duration::from_millis(250), config.broadcast.interval);
+        assert_eq!(Color([0, 0, 0]), config.colors.filled);
+        assert_eq!(1, config.trusted_proxies.len());
+        assert_eq!(Duration::from_secs(30), config.storage.save_interval);
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        let resolve = |toml: &str| toml::from_str::<Settings>(toml).unwrap().resolve();
+        assert!(resolve("width = 2000\nheight = 1000").is_err());
+        assert!(resolve("broadcast_channel_size = 0").is_err());
+        assert!(resolve("save_interval = 0").is_err());
+        assert!(resolve("cursor_interval = 0").is_err());
+        assert!(resolve("pong_timeout = 0").is_err());
+        assert!(resolve("broadcast_flush_threshold = 0").is_err());
+        assert!(resolve("tls_cert = \"cert.pem\"").is_err());
+        assert!(resolve("unix_socket = \"a.sock\"\nport = 80").is_err());
+        assert!(toml::from_str::<Settings>("colour = \"#fff\"").is_err());
+        assert!(toml::from_str::<Settings>("empty_color = \"#fff\"").is_err());
+        assert!(resolve("admin_token = \"hunter2\"").is_err());
+        assert!(resolve("width = 800\nheight = 600").is_ok());
+    }
+
+    #[test]
+    fn regions_live_next_to_the_dump() {
+        let resolve = |toml: &str| toml::from_str::<Settings>(toml).unwrap().resolve().unwrap();
+        assert_eq!("regions.json", resolve("").storage.regions_path);
+        assert_eq!(
+            "/var/lib/blobgrid/regions.json",
+            resolve("dump_path = \"/var/lib/blobgrid/dump.bin\"")
+                .storage
+                .regions_path
+        );
+        assert_eq!(
+            "rules.json",
+            resolve("dump_path = \"/srv/dump.bin\"\nregions_path = \"rules.json\"")
+                .storage
+                .regions_path
+        );
+    }
+
+    #[test]
+    fn printed_config_reads_back() {
+        let config = Settings::default().resolve().unwrap();
+        let printed = toml::to_string(&Settings::effective(&config)).unwrap();
+        let again = toml::from_str::<Settings>(&printed)
+            .unwrap()
+            .resolve()
+            .unwrap();
+        assert_eq!(config.listen.port, again.listen.port);
+        assert_eq!(config.broadcast.interval, again.broadcast.interval);
+        assert_eq!(config.colors.empty, again.colors.empty);
+
+        let secret = toml::from_str::<Settings>("admin_token = \"0123456789abcdef\"")
+            .unwrap()
+            .resolve()
+            .unwrap();
+        let printed = toml::to_string(&Settings::printable(&secret)).unwrap();
+        assert!(printed.contains("admin_token = \"<set>\""));
+        assert!(!printed.contains("0123456789abcdef"));
+    }
+
+    #[test]
+    fn changes_tell_live_settings_apart() {
+        let resolve = |toml: &str| toml::from_str::<Settings>(toml).unwrap().resolve().unwrap();
+        let old = resolve("write_burst = 100\nport = 4000");
+        let new = resolve("banned = [\"10.0.0.0/8\"]\nport = 4001");
+
+        let changes = changes(&old, &new);
+        let keys: Vec<_> = changes.iter().map(|change| change.key.as_str()).collect();
+        assert_eq!(vec!["banned", "port", "write_burst"], keys);
+        assert_eq!("write_burst 100 -> unset", changes[2].to_string());
+        assert!(changes[0].is_live());
+        assert!(!changes[1].is_live());
+
+        let live = new.live();
+        assert!(live.is_banned("10.1.2.3".parse().unwrap()));
+        assert!(!live.is_banned("11.1.2.3".parse().unwrap()));
+        assert!(super::changes(&old.with_live(&live), &new)
+            .iter()
+            .all(|change| !change.is_live()));
+    }
 }
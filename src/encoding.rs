@@ -0,0 +1,277 @@
+use std::io::Write;
+
+use flate2::{write::GzEncoder, Compression};
+
+/// Encodings of the full board offered by `/api/grid`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BoardEncoding {
+    Base64,
+    Raw,
+    Rle,
+}
+
+impl BoardEncoding {
+    /// Picks an encoding from an `Accept` header, base64 unless the client
+    /// prefers one of the binary formats.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let accepted = weighted(accept);
+        [
+            (BoardEncoding::Rle, RLE_CONTENT_TYPE),
+            (BoardEncoding::Raw, "application/octet-stream"),
+            (BoardEncoding::Base64, "text/plain"),
+        ]
+        .into_iter()
+        .map(|(encoding, media_type)| (encoding, quality(&accepted, media_type)))
+        .fold(None, best)
+        .map_or(BoardEncoding::Base64, |(encoding, _)| encoding)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            BoardEncoding::Base64 => "text/plain; charset=utf-8",
+            BoardEncoding::Raw => "application/octet-stream",
+            BoardEncoding::Rle => RLE_CONTENT_TYPE,
+        }
+    }
+
+    /// Short name used to tell representations apart in ETags.
+    pub fn tag(&self) -> &'static str {
+        match self {
+            BoardEncoding::Base64 => "b64",
+            BoardEncoding::Raw => "raw",
+            BoardEncoding::Rle => "rle",
+        }
+    }
+}
+
+pub const RLE_CONTENT_TYPE: &str = "application/vnd.blobgrid.rle";
+
+/// Transfer compression applied on top of a board encoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContentCoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl ContentCoding {
+    /// Picks the best coding from an `Accept-Encoding` header, preferring
+    /// brotli over gzip when the client weighs them the same.
+    pub fn negotiate(accept_encoding: Option<&str>) -> Self {
+        let accepted = weighted(accept_encoding);
+        [(ContentCoding::Brotli, "br"), (ContentCoding::Gzip, "gzip")]
+            .into_iter()
+            .map(|(coding, name)| (coding, quality(&accepted, name)))
+            .fold(None, best)
+            .map_or(ContentCoding::Identity, |(coding, _)| coding)
+    }
+
+    /// Value for the `Content-Encoding` header, none for identity.
+    pub fn header(&self) -> Option<&'static str> {
+        match self {
+            ContentCoding::Identity => None,
+            ContentCoding::Gzip => Some("gzip"),
+            ContentCoding::Brotli => Some("br"),
+        }
+    }
+
+    /// Short name used to tell representations apart in ETags.
+    pub fn tag(&self) -> &'static str {
+        match self {
+            ContentCoding::Identity => "id",
+            ContentCoding::Gzip => "gz",
+            ContentCoding::Brotli => "br",
+        }
+    }
+
+    pub fn compress(&self, data: Vec<u8>) -> Vec<u8> {
+        match self {
+            ContentCoding::Identity => data,
+            ContentCoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder
+                    .write_all(&data)
+                    .and_then(|_| encoder.finish())
+                    .expect("Writing to a Vec never fails")
+            }
+            ContentCoding::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 6, 22);
+                    writer
+                        .write_all(&data)
+                        .expect("Writing to a Vec never fails");
+                }
+                out
+            }
+        }
+    }
+}
+
+/// Items of an `Accept` style header with their `q` weights, 1 when unset.
+fn weighted(header: Option<&str>) -> Vec<(&str, f32)> {
+    header
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next().filter(|name| !name.is_empty())?;
+            let q = parts
+                .filter_map(|param| param.strip_prefix("q=").or(param.strip_prefix("Q=")))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((name, q))
+        })
+        .collect()
+}
+
+/// Weight the client gave `name`, 0 when it wasn't listed.
+fn quality(accepted: &[(&str, f32)], name: &str) -> f32 {
+    accepted
+        .iter()
+        .find(|(item, _)| item.eq_ignore_ascii_case(name))
+        .map_or(0.0, |&(_, q)| q)
+}
+
+/// Keeps the first candidate with the highest weight above 0.
+fn best<T>(chosen: Option<(T, f32)>, candidate: (T, f32)) -> Option<(T, f32)> {
+    match chosen {
+        Some(chosen) if chosen.1 >= candidate.1 => Some(chosen),
+        _ if candidate.1 > 0.0 => Some(candidate),
+        chosen => chosen,
+    }
+}
+
+/// Zero-run encoding for mostly empty boards.
+///
+/// The output is a sequence of records, each a LEB128 count of zero bytes, a
+/// LEB128 count of literal bytes and then the literal bytes themselves.
+pub fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let zeros = data[pos..].iter().take_while(|byte| **byte == 0).count();
+        pos += zeros;
+        let literals = data[pos..]
+            .windows(2)
+            .position(|pair| pair == [0, 0])
+            .unwrap_or(data.len() - pos);
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, literals);
+        out.extend_from_slice(&data[pos..pos + literals]);
+        pos += literals;
+    }
+    out
+}
+
+#[cfg(test)]
+pub fn rle_decode(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    while !data.is_empty() {
+        let zeros = read_varint(&mut data)?;
+        let literals = read_varint(&mut data)?;
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(data.get(..literals)?);
+        data = &data[literals..];
+    }
+    Some(out)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+fn read_varint(data: &mut &[u8]) -> Option<usize> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rle_round_trip() {
+        let mut board = vec![0u8; 125000];
+        board[10] = 1;
+        board[11] = 0;
+        board[12] = 255;
+        board[70000] = 8;
+        board[124999] = 3;
+
+        let encoded = rle_encode(&board);
+        assert!(encoded.len() < 32);
+        assert_eq!(Some(board), rle_decode(&encoded));
+
+        let dense: Vec<u8> = (0..=255).collect();
+        assert_eq!(Some(dense.clone()), rle_decode(&rle_encode(&dense)));
+        assert_eq!(Some(vec![]), rle_decode(&rle_encode(&[])));
+    }
+
+    #[test]
+    fn negotiate_encoding() {
+        assert_eq!(BoardEncoding::Base64, BoardEncoding::negotiate(None));
+        assert_eq!(
+            BoardEncoding::Raw,
+            BoardEncoding::negotiate(Some("application/octet-stream"))
+        );
+        assert_eq!(
+            BoardEncoding::Rle,
+            BoardEncoding::negotiate(Some(
+                "application/vnd.blobgrid.rle, application/octet-stream;q=0.5"
+            ))
+        );
+        assert_eq!(
+            BoardEncoding::Base64,
+            BoardEncoding::negotiate(Some("application/octet-stream;q=0, */*"))
+        );
+        assert_eq!(
+            BoardEncoding::Raw,
+            BoardEncoding::negotiate(Some(
+                "application/vnd.blobgrid.rle;q=0.2, application/octet-stream;q=0.9"
+            ))
+        );
+        assert_eq!(
+            BoardEncoding::Base64,
+            BoardEncoding::negotiate(Some("text/plain, application/octet-stream;q=0.5"))
+        );
+    }
+
+    #[test]
+    fn negotiate_coding() {
+        assert_eq!(ContentCoding::Identity, ContentCoding::negotiate(None));
+        assert_eq!(
+            ContentCoding::Brotli,
+            ContentCoding::negotiate(Some("gzip, deflate, br"))
+        );
+        assert_eq!(
+            ContentCoding::Gzip,
+            ContentCoding::negotiate(Some("br;q=0, gzip;q=0.8"))
+        );
+        assert_eq!(
+            ContentCoding::Identity,
+            ContentCoding::negotiate(Some("zstd"))
+        );
+        assert_eq!(
+            ContentCoding::Gzip,
+            ContentCoding::negotiate(Some("br;q=0.5, gzip"))
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::RwLock;
 
 use crate::bit_utils::{get_bit, set_bit, toggle_bit};
 
@@ -31,14 +31,22 @@ impl Chunk {
         false
     }
 
-    async fn set_bit(&self, byte_offset: usize, bit_position: usize, value: bool) {
+    pub(crate) async fn set_bit(
+        &self,
+        byte_offset: usize,
+        bit_position: usize,
+        value: bool,
+    ) -> bool {
         let mut chunk_data = self.data.write().await;
         if bit_position < 8 {
-            chunk_data[byte_offset] = set_bit(chunk_data[byte_offset], bit_position, value);
+            let old_byte = chunk_data[byte_offset];
+            chunk_data[byte_offset] = set_bit(old_byte, bit_position, value);
+            return chunk_data[byte_offset] != old_byte;
         }
+        false
     }
 
-    async fn read_bit(&self, byte_offset: usize, bit_position: usize) -> bool {
+    pub(crate) async fn read_bit(&self, byte_offset: usize, bit_position: usize) -> bool {
         let chunk_data = self.data.read().await;
         if bit_position < 8 {
             get_bit(chunk_data[byte_offset], bit_position)
@@ -1,8 +1,6 @@
 use std::array;
 
-use base64::{prelude::BASE64_STANDARD, Engine};
-
-use crate::grid::{Grid, SubRectInfo};
+use crate::grid::Grid;
 
 use super::{chunk::Chunk, CHUNK_SIZE, MAX_SIZE, NUM_CHUNKS};
 
@@ -44,31 +42,20 @@ impl Grid for Grid2 {
         chunk.toggle_bit(offset_within_chunk, bit_position).await // Toggle the bit in the chunk
     }
 
-    async fn get_rect(
-        &self,
-        bytes_x: usize,
-        bytes_y: usize,
-        bytes_width: usize,
-        height: usize,
-    ) -> SubRectInfo {
-        let mut result = vec![0; bytes_width * height];
-        let canvas_width = 1000;
-        for y in 0..height {
-            let global_y = bytes_y + y;
-            let row = self.chunks[global_y].data.read().await;
-            for x in 0..bytes_width {
-                let global_x = bytes_x + x;
-                result[bytes_width * y + x] = row[global_x]
-            }
-        }
-        SubRectInfo {
-            data: result,
-            x_shift: bytes_x * 8,
-            y_shift: bytes_y,
-            width: bytes_width * 8,
-            height: height,
-            canvas_width: canvas_width,
-        }
+    async fn read_item(&self, bit_index: usize) -> bool {
+        let (byte_index, bit_position) = Self::get_bit_info(bit_index);
+        let (chunk_index, offset_within_chunk) = Self::get_chunk_info(byte_index);
+        self.chunks[chunk_index]
+            .read_bit(offset_within_chunk, bit_position)
+            .await
+    }
+
+    async fn write_item(&mut self, bit_index: usize, value: bool) -> bool {
+        let (byte_index, bit_position) = Self::get_bit_info(bit_index);
+        let (chunk_index, offset_within_chunk) = Self::get_chunk_info(byte_index);
+        self.chunks[chunk_index]
+            .set_bit(offset_within_chunk, bit_position, value)
+            .await
     }
 }
 
@@ -1,10 +1,75 @@
 use std::fmt::Debug;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::bit_utils::{get_bit, set_bit, toggle_bit};
+use crate::bit_utils::set_bit;
 
 pub const MAX_SIZE: usize = 125000;
+pub const WIDTH: usize = 1000;
+pub const HEIGHT: usize = MAX_SIZE * 8 / WIDTH;
+
+/// Drawable part of the board, stored row by row from the first bit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Canvas {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Canvas {
+    pub fn pixels(&self) -> usize {
+        self.width * self.height
+    }
+
+    /// Linear index of the pixel at `x`, `y` if it is on the canvas.
+    pub fn index(&self, x: usize, y: usize) -> Option<usize> {
+        (x < self.width && y < self.height).then_some(y * self.width + x)
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        index < self.pixels()
+    }
+
+    pub fn position(&self, index: usize) -> (usize, usize) {
+        (index % self.width, index / self.width)
+    }
+}
+
+/// A rectangle of pixels with its top left corner at `x`, `y`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    /// Whether the rectangle is non-empty and lies on the canvas.
+    pub fn fits(&self, canvas: Canvas) -> bool {
+        let right = self.x.checked_add(self.width);
+        let bottom = self.y.checked_add(self.height);
+        self.width > 0
+            && self.height > 0
+            && right.is_some_and(|right| right <= canvas.width)
+            && bottom.is_some_and(|bottom| bottom <= canvas.height)
+    }
+
+    /// Indices of the pixels inside the rectangle that are on the canvas.
+    pub fn indices(self, canvas: Canvas) -> impl Iterator<Item = usize> {
+        let columns = self.x..self.x.saturating_add(self.width).min(canvas.width);
+        (self.y..self.y.saturating_add(self.height).min(canvas.height))
+            .flat_map(move |y| columns.clone().map(move |x| y * canvas.width + x))
+    }
+}
+
+impl Default for Canvas {
+    fn default() -> Self {
+        Self {
+            width: WIDTH,
+            height: HEIGHT,
+        }
+    }
+}
 
 pub trait Grid {
     fn new() -> Self;
@@ -12,15 +77,44 @@ pub trait Grid {
     async fn get_full(&self) -> [u8; MAX_SIZE];
     async fn set_full(&mut self, data: [u8; MAX_SIZE]);
 
+    /// Pixels of a rectangle of `canvas`, `bytes_width` bytes wide, packed
+    /// row by row like the board.
     async fn get_rect(
         &self,
+        canvas: Canvas,
         bytes_x: usize,
         bytes_y: usize,
         bytes_width: usize,
         height: usize,
-    ) -> SubRectInfo;
+    ) -> SubRectInfo {
+        let mut data = vec![0; bytes_width * height];
+        for y in 0..height {
+            for x in 0..bytes_width * 8 {
+                let Some(index) = canvas.index(bytes_x * 8 + x, bytes_y + y) else {
+                    continue;
+                };
+                if self.read_item(index).await {
+                    let byte = &mut data[bytes_width * y + x / 8];
+                    *byte = set_bit(*byte, x % 8, true);
+                }
+            }
+        }
+        SubRectInfo {
+            data,
+            x_shift: bytes_x * 8,
+            y_shift: bytes_y,
+            width: bytes_width * 8,
+            height,
+            canvas_width: canvas.width,
+        }
+    }
 
     async fn toggle_item(&mut self, index: usize) -> bool;
+
+    async fn read_item(&self, index: usize) -> bool;
+
+    /// Sets the bit and reports whether it changed.
+    async fn write_item(&mut self, index: usize, value: bool) -> bool;
 }
 
 #[derive(Debug, Serialize)]
@@ -32,3 +126,52 @@ pub struct SubRectInfo {
     pub height: usize,
     pub canvas_width: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fine_grained::Grid2;
+
+    #[test]
+    fn rect_stays_on_canvas() {
+        let canvas = Canvas {
+            width: 10,
+            height: 5,
+        };
+        let rect = Rect {
+            x: 8,
+            y: 3,
+            width: 2,
+            height: 2,
+        };
+        assert!(rect.fits(canvas));
+        assert_eq!(
+            vec![38, 39, 48, 49],
+            rect.indices(canvas).collect::<Vec<_>>()
+        );
+
+        let wider = Rect { width: 3, ..rect };
+        assert!(!wider.fits(canvas));
+        assert_eq!(4, wider.indices(canvas).count());
+        assert!(!Rect { width: 0, ..rect }.fits(canvas));
+        assert!(!Rect {
+            x: usize::MAX,
+            ..rect
+        }
+        .fits(canvas));
+    }
+
+    #[tokio::test]
+    async fn rect_of_narrow_canvas() {
+        // Rows of a canvas narrower than a byte multiple aren't byte aligned
+        let canvas = Canvas {
+            width: 13,
+            height: 10,
+        };
+        let mut grid = Grid2::new();
+        grid.write_item(13 + 9, true).await;
+        let rect = grid.get_rect(canvas, 1, 1, 1, 1).await;
+        assert_eq!(vec![0b10], rect.data);
+        assert_eq!(13, rect.canvas_width);
+    }
+}
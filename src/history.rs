@@ -0,0 +1,169 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use crate::broadcast::Batch;
+
+/// Recently broadcast batches, kept so clients can catch up after a gap.
+pub struct History {
+    batches: VecDeque<Arc<Batch>>,
+    capacity: usize,
+    last_seq: u64,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            batches: VecDeque::with_capacity(capacity),
+            capacity,
+            last_seq: 0,
+        }
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Forgets every batch, clients from before `seq` need a snapshot.
+    pub fn restart(&mut self, seq: u64) {
+        self.batches.clear();
+        self.last_seq = seq;
+    }
+
+    /// Number of batches kept.
+    pub fn len(&self) -> usize {
+        self.batches.len()
+    }
+
+    pub fn push(&mut self, batch: Arc<Batch>) {
+        self.last_seq = batch.seq;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.batches.len() == self.capacity {
+            self.batches.pop_front();
+        }
+        self.batches.push_back(batch);
+    }
+
+    /// Batches that follow `seq`, or `None` when part of that range is gone.
+    pub fn since(&self, seq: u64) -> Option<Vec<Arc<Batch>>> {
+        let oldest = self
+            .batches
+            .front()
+            .map(|batch| batch.from)
+            .unwrap_or(self.last_seq);
+        if seq < oldest {
+            return None;
+        }
+        Some(
+            self.batches
+                .iter()
+                .filter(|batch| batch.seq > seq)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Everything that changed after `seq` folded into one batch holding
+    /// final values, or `None` when the range can't be rebuilt from history.
+    pub fn changes_since(&self, seq: u64) -> Option<Batch> {
+        if seq > self.last_seq {
+            // Comes from another run of the server
+            return None;
+        }
+        let mut pixels = HashMap::new();
+        for batch in self.since(seq)? {
+            pixels.extend(batch.off.iter().map(|&index| (index, false)));
+            pixels.extend(batch.on.iter().map(|&index| (index, true)));
+        }
+        let (mut on, mut off) = (vec![], vec![]);
+        for (index, value) in pixels {
+            if value {
+                on.push(index);
+            } else {
+                off.push(index);
+            }
+        }
+        on.sort_unstable();
+        off.sort_unstable();
+        Some(Batch {
+            seq: self.last_seq,
+            from: seq,
+            on,
+            off,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(from: u64, seq: u64) -> Arc<Batch> {
+        Arc::new(Batch {
+            seq,
+            from,
+            on: vec![seq as usize],
+            off: vec![],
+        })
+    }
+
+    #[test]
+    fn since_replays_following_batches() {
+        let mut history = History::new(10);
+        history.push(batch(0, 3));
+        history.push(batch(3, 5));
+        history.push(batch(5, 9));
+
+        let seqs = |batches: Vec<Arc<Batch>>| batches.iter().map(|b| b.seq).collect::<Vec<_>>();
+        assert_eq!(vec![3, 5, 9], seqs(history.since(0).unwrap()));
+        assert_eq!(vec![5, 9], seqs(history.since(4).unwrap()));
+        assert_eq!(Vec::<u64>::new(), seqs(history.since(9).unwrap()));
+    }
+
+    #[test]
+    fn since_reports_evicted_range() {
+        let mut history = History::new(2);
+        history.push(batch(0, 3));
+        history.push(batch(3, 5));
+        history.push(batch(5, 9));
+
+        assert!(history.since(2).is_none());
+        assert_eq!(2, history.since(3).unwrap().len());
+    }
+
+    #[test]
+    fn restart_sends_everyone_to_a_snapshot() {
+        let mut history = History::new(10);
+        history.push(batch(0, 3));
+        history.restart(7);
+        assert_eq!(7, history.last_seq());
+        assert!(history.since(3).is_none());
+        assert!(history.since(7).unwrap().is_empty());
+    }
+
+    #[test]
+    fn changes_keep_final_values() {
+        let mut history = History::new(10);
+        let push = |history: &mut History, from, seq, on: Vec<usize>, off: Vec<usize>| {
+            history.push(Arc::new(Batch { seq, from, on, off }));
+        };
+        push(&mut history, 0, 3, vec![1, 2], vec![]);
+        push(&mut history, 3, 5, vec![4], vec![1]);
+        push(&mut history, 5, 9, vec![1], vec![2]);
+
+        let changes = history.changes_since(0).unwrap();
+        assert_eq!(9, changes.seq);
+        assert_eq!(vec![1, 4], changes.on);
+        assert_eq!(vec![2], changes.off);
+
+        let changes = history.changes_since(5).unwrap();
+        assert_eq!(vec![1], changes.on);
+        assert_eq!(vec![2], changes.off);
+
+        assert!(history.changes_since(9).unwrap().on.is_empty());
+        assert!(history.changes_since(10).is_none());
+    }
+}
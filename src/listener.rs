@@ -0,0 +1,274 @@
+use std::{
+    fs::File,
+    future::Future,
+    io::{self, BufReader},
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, service::service_fn, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::RwLock,
+};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+use tower::ServiceExt;
+
+/// How long open requests, event streams mostly, may hold up a shutdown
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// How long a client gets to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the server takes connections from.
+pub enum Listener {
+    Tcp {
+        listener: TcpListener,
+        tls: Option<Tls>,
+    },
+    /// Peers on a Unix socket count as the loopback address, so a local
+    /// proxy can be trusted with `--trusted-proxy 127.0.0.1/32`
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(addr: SocketAddr, tls: Option<Tls>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Listener::Tcp { listener, tls })
+    }
+
+    /// Listens on a Unix socket, replacing one left behind by an earlier run.
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path) -> io::Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(Listener::Unix(UnixListener::bind(path)?))
+    }
+
+    /// Certificate of an HTTPS listener, to reload when renewed.
+    pub fn tls(&self) -> Option<&Tls> {
+        match self {
+            Listener::Tcp { tls, .. } => tls.as_ref(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Listener::Tcp { listener, tls } => {
+                let scheme = if tls.is_some() { "https" } else { "http" };
+                match listener.local_addr() {
+                    Ok(addr) => format!("{}://{}", scheme, addr),
+                    Err(_) => scheme.to_owned(),
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => match listener.local_addr() {
+                Ok(addr) => format!(
+                    "unix socket {:?}",
+                    addr.as_pathname().unwrap_or(Path::new(""))
+                ),
+                Err(_) => "unix socket".to_owned(),
+            },
+        }
+    }
+
+    async fn accept(&self) -> io::Result<(Accepted, SocketAddr)> {
+        match self {
+            Listener::Tcp { listener, tls } => {
+                let (stream, peer) = listener.accept().await?;
+                let accepted = match tls {
+                    Some(tls) => Accepted::Tls(stream, tls.acceptor().await),
+                    None => Accepted::Plain(Box::new(stream)),
+                };
+                Ok((accepted, peer))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+                Ok((Accepted::Plain(Box::new(stream)), peer))
+            }
+        }
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Stream for T {}
+
+/// A connection that may still need its TLS handshake, done off the
+/// accept loop so a slow client can't hold up everyone else.
+enum Accepted {
+    Plain(Box<dyn Stream>),
+    Tls(TcpStream, TlsAcceptor),
+}
+
+impl Accepted {
+    async fn establish(self) -> io::Result<Box<dyn Stream>> {
+        match self {
+            Accepted::Plain(stream) => Ok(stream),
+            Accepted::Tls(stream, acceptor) => {
+                let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")
+                    })??;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+/// Certificate and key for HTTPS, read again on [`Tls::reload`].
+#[derive(Clone)]
+pub struct Tls {
+    cert: PathBuf,
+    key: PathBuf,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl Tls {
+    pub fn load(cert: &Path, key: &Path) -> io::Result<Self> {
+        let config = server_config(cert, key)?;
+        Ok(Self {
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+            acceptor: Arc::new(RwLock::new(TlsAcceptor::from(Arc::new(config)))),
+        })
+    }
+
+    /// Picks up renewed files for new connections, keeping the current
+    /// certificate when the new one can't be loaded.
+    pub async fn reload(&self) -> io::Result<()> {
+        let config = server_config(&self.cert, &self.key)?;
+        *self.acceptor.write().await = TlsAcceptor::from(Arc::new(config));
+        Ok(())
+    }
+
+    async fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().await.clone()
+    }
+}
+
+fn server_config(cert: &Path, key: &Path) -> io::Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No certificate found",
+        ));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No private key found"))?;
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Serves `app` until `shutdown` resolves, then gives open requests a
+/// moment to finish.
+pub async fn serve(listener: Listener, app: Router, shutdown: impl Future<Output = ()>) {
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        let (accepted, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Usually out of file descriptors, give some a chance to close
+                log::warn!("Failed to accept a connection: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let app = app.clone();
+        let builder = builder.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let stream = match accepted.establish().await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::debug!("TLS handshake with {} failed: {}", peer, err);
+                    return;
+                }
+            };
+            let service = service_fn(move |mut request: Request<Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(peer));
+                app.clone().oneshot(request)
+            });
+            let connection = builder
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .into_owned();
+            if let Err(err) = watcher.watch(connection).await {
+                log::debug!("Connection from {} ended with an error: {}", peer, err);
+            }
+        });
+    }
+
+    if tokio::time::timeout(SHUTDOWN_GRACE, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        log::info!("Closing connections still open after shutdown");
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unix_peers_are_loopback() {
+        let path = std::env::temp_dir().join(format!("blobgrid-{}.sock", std::process::id()));
+        let listener = Listener::bind_unix(&path).unwrap();
+        let app = Router::new().route(
+            "/",
+            get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.ip().to_string() }),
+        );
+        let server = tokio::spawn(serve(listener, app, std::future::pending()));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n127.0.0.1"), "{}", response);
+
+        server.abort();
+        std::fs::remove_file(path).unwrap();
+    }
+}
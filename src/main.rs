@@ -1,18 +1,37 @@
-use std::{fs, net::SocketAddr};
+use std::net::SocketAddr;
 
 use clap::Parser;
-use config::Cli;
+use config::{Cli, Command, ListenConfig, Settings};
+use listener::{Listener, Tls};
 use server::router;
 use state::AppState;
-use tokio::signal;
+use tokio::{signal, time};
 
+mod admin;
+mod assets;
+mod audit;
 mod bit_utils;
+mod broadcast;
+mod client;
 mod config;
+mod encoding;
 mod fine_grained;
 mod grid;
-mod grid1;
+mod history;
+mod listener;
+mod mode;
+mod presence;
+mod raster;
+mod rate_limit;
+mod regions;
+mod reload;
+mod rollback;
 mod server;
+mod snapshot;
+mod sse;
 mod state;
+mod tiles;
+mod write;
 mod ws;
 
 #[tokio::main]
@@ -20,29 +39,58 @@ async fn main() {
     env_logger::init();
 
     let cli = Cli::parse();
+    let config = cli.load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(2)
+    });
+    if cli.print_config {
+        match toml::to_string(&Settings::printable(&config)) {
+            Ok(toml) => print!("{}", toml),
+            Err(err) => eprintln!("Failed to print settings: {}", err),
+        }
+        return;
+    }
+    if let Some(Command::Rollback(args)) = &cli.command {
+        if let Err(err) = rollback::run(&config, args) {
+            eprintln!("{}", err);
+            std::process::exit(1)
+        }
+        return;
+    }
 
-    let dump_path = cli.dump_path.unwrap_or("dump.bin".to_owned());
-    let bitmap_path = cli.bitmap_path.unwrap_or("dump.png".to_owned());
-
-    let mut state = AppState::new(&dump_path, &bitmap_path);
+    let listen_config = config.listen.clone();
+    let mut state = AppState::new(config);
     log::info!("Loading data");
     state.load().await;
 
     tokio::spawn(periodic_save(state.clone()));
 
     let app = router(state.clone());
-    log::info!("Starting");
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", cli.port.unwrap_or(3000)))
+    let listener = listen(&listen_config).await;
+    #[cfg(unix)]
+    tokio::spawn(reload::reload_on_hangup(
+        cli,
+        state.clone(),
+        listener.tls().cloned(),
+    ));
+    log::info!("Starting on {}", listener.describe());
+    listener::serve(listener, app, shutdown_signal(state)).await;
+}
+
+async fn listen(config: &ListenConfig) -> Listener {
+    #[cfg(unix)]
+    if let Some(path) = &config.unix_socket {
+        return Listener::bind_unix(path).expect("Failed to create listener");
+    }
+
+    let tls = config
+        .tls
+        .as_ref()
+        .map(|tls| Tls::load(&tls.cert, &tls.key).expect("Failed to load TLS certificate"));
+    let addr = SocketAddr::new(config.bind, config.port);
+    Listener::bind(addr, tls)
         .await
-        .expect("Failed to create listener");
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .with_graceful_shutdown(shutdown_signal(state))
-    .await
-    .expect("Failed to start server");
+        .expect("Failed to create listener")
 }
 
 async fn shutdown_signal(state: AppState) {
@@ -66,25 +114,41 @@ async fn shutdown_signal(state: AppState) {
     tokio::select! {
         _ = ctrl_c => {
             log::info!("Dumping data by ctrl-c");
-            fs::write(&state.dump_path, state.save().await);
-            state.save_png(&state.bitmap_path).await;
+            dump(&state).await;
             log::info!("Finished");
         },
         _ = terminate => {
             log::info!("Dumping data by terminate");
-            fs::write(&state.dump_path, state.save().await);
-            state.save_png(&state.bitmap_path).await;
+            dump(&state).await;
             log::info!("Finished")
         },
     }
+    state.audit.sync().await;
 }
 
 async fn periodic_save(state: AppState) {
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(30000));
+    let mut live = state.live.subscribe();
+    let mut every = live.borrow_and_update().save_interval;
+    let mut interval = time::interval(every);
     loop {
-        interval.tick().await;
+        tokio::select! {
+            _ = interval.tick() => {}
+            Ok(()) = live.changed() => {
+                let save_interval = live.borrow_and_update().save_interval;
+                if save_interval != every {
+                    every = save_interval;
+                    interval = time::interval_at(time::Instant::now() + every, every);
+                }
+                continue;
+            }
+        }
         log::info!("Saving backup at {:?}", tokio::time::Instant::now());
-        fs::write(&state.dump_path, state.save().await);
-        state.save_png(&state.bitmap_path).await;
+        dump(&state).await;
+    }
+}
+
+async fn dump(state: &AppState) {
+    if let Err(err) = state.dump().await {
+        log::error!("Failed to write dump to {}: {}", state.dump_path, err);
     }
 }
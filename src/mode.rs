@@ -0,0 +1,106 @@
+use std::fmt;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{broadcast::Event, state::AppState, write::WriteError};
+
+/// Who may write to the board.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    #[default]
+    Open,
+    // The board stays viewable and live, writes are turned away
+    ReadOnly,
+    // Writes are turned away and new clients are told why
+    Maintenance,
+}
+
+impl Mode {
+    /// Why a regular write can't go through in this mode.
+    pub fn check_write(self) -> Result<(), WriteError> {
+        match self {
+            Mode::Open => Ok(()),
+            Mode::ReadOnly => Err(WriteError::ReadOnly),
+            Mode::Maintenance => Err(WriteError::Maintenance),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.to_possible_value().expect("No mode is skipped");
+        f.write_str(name.get_name())
+    }
+}
+
+/// The mode together with the message shown to clients while it lasts.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct BoardMode {
+    pub mode: Mode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notice: Option<String>,
+}
+
+impl From<Mode> for BoardMode {
+    fn from(mode: Mode) -> Self {
+        Self { mode, notice: None }
+    }
+}
+
+impl AppState {
+    /// Switches the board to `mode` and tells every connected client.
+    pub async fn set_mode(&self, mode: BoardMode) {
+        let old = self.mode.send_replace(mode.clone());
+        if old == mode {
+            return;
+        }
+        log::warn!("Board is now {}", mode.mode);
+        let _ = self.broadcast.lock().await.send(Event::mode(&mode));
+    }
+
+    /// What a client joining now should be told about the mode, nothing
+    /// while the board is open.
+    pub fn mode_notice(&self) -> Option<Event> {
+        let mode = self.mode.borrow();
+        (mode.mode != Mode::Open).then(|| Event::mode(&mode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+    use crate::{client::Client, config::Config};
+
+    #[tokio::test]
+    async fn closed_board_rejects_writes_and_announces_itself() {
+        let state = AppState::new(Config::scratch("mode"));
+        let client = Client::http(IpAddr::from([127, 0, 0, 1]));
+        let mut receiver = state.broadcast.lock().await.subscribe();
+        assert!(state.mode_notice().is_none());
+
+        let read_only = BoardMode {
+            mode: Mode::ReadOnly,
+            notice: Some("Thanks for drawing".into()),
+        };
+        state.set_mode(read_only.clone()).await;
+        assert_eq!(Err(WriteError::ReadOnly), state.toggle(client, 0).await);
+        let event = receiver.recv().await.unwrap();
+        assert_eq!("mode", event.name);
+        assert_eq!(
+            r#"{"type":"mode","mode":"read-only","notice":"Thanks for drawing"}"#,
+            &*event.data
+        );
+        assert_eq!(event.data, state.mode_notice().unwrap().data);
+
+        // Nothing new to tell
+        state.set_mode(read_only).await;
+        state.set_mode(BoardMode::default()).await;
+        assert_eq!("mode", receiver.recv().await.unwrap().name);
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(Ok(true), state.toggle(client, 0).await);
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use axum::extract::ws::{close_code, CloseFrame};
+
+use serde::Serialize;
+use tokio::time;
+
+use crate::{
+    broadcast::Event,
+    config::{ConnectionConfig, PresenceConfig},
+    state::AppState,
+};
+
+pub struct Session {
+    pub ip: IpAddr,
+    pub connected_at: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Cursor {
+    pub session: u64,
+    pub x: u32,
+    pub y: u32,
+}
+
+/// Why a websocket was turned away.
+#[derive(Debug, PartialEq)]
+pub enum Refusal {
+    ServerFull,
+    TooManyFromAddress,
+    TooFast,
+    Banned,
+}
+
+impl Refusal {
+    pub fn close_frame(&self) -> CloseFrame<'static> {
+        let (code, reason) = match self {
+            Refusal::ServerFull => (close_code::AGAIN, "Server is full"),
+            Refusal::TooManyFromAddress => {
+                (close_code::POLICY, "Too many connections from your address")
+            }
+            Refusal::TooFast => (close_code::POLICY, "Connecting too fast"),
+            Refusal::Banned => (close_code::POLICY, "Banned"),
+        };
+        CloseFrame {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Connection attempts from one address within the current second.
+struct Attempts {
+    window_start: Instant,
+    count: u32,
+}
+
+/// Every open websocket session and the cursors waiting to be relayed.
+pub struct Connections {
+    next_id: u64,
+    sessions: HashMap<u64, Session>,
+    per_ip: HashMap<IpAddr, usize>,
+    attempts: HashMap<IpAddr, Attempts>,
+    attempts_pruned: Instant,
+    moved: HashMap<u64, Cursor>,
+    gone: HashSet<u64>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self {
+            next_id: 1,
+            sessions: HashMap::new(),
+            per_ip: HashMap::new(),
+            attempts: HashMap::new(),
+            attempts_pruned: Instant::now(),
+            moved: HashMap::new(),
+            gone: HashSet::new(),
+        }
+    }
+
+    /// Registers a session unless that would break one of the limits.
+    pub fn admit(&mut self, ip: IpAddr, limits: &ConnectionConfig) -> Result<u64, Refusal> {
+        let now = Instant::now();
+
+        if let Some(max) = limits.max_connects_per_sec {
+            // At most once per window rather than on every attempt
+            if self.attempts.len() > 1024 && now - self.attempts_pruned >= Duration::from_secs(1) {
+                self.attempts_pruned = now;
+                self.attempts
+                    .retain(|_, attempts| now - attempts.window_start < Duration::from_secs(1));
+            }
+            let attempts = self.attempts.entry(ip).or_insert(Attempts {
+                window_start: now,
+                count: 0,
+            });
+            if now - attempts.window_start >= Duration::from_secs(1) {
+                attempts.window_start = now;
+                attempts.count = 0;
+            }
+            attempts.count += 1;
+            if attempts.count > max {
+                return Err(Refusal::TooFast);
+            }
+        }
+        if limits
+            .max_sessions
+            .is_some_and(|max| self.sessions.len() >= max)
+        {
+            return Err(Refusal::ServerFull);
+        }
+        if limits
+            .max_sessions_per_ip
+            .is_some_and(|max| self.per_ip.get(&ip).copied().unwrap_or(0) >= max)
+        {
+            return Err(Refusal::TooManyFromAddress);
+        }
+        Ok(self.register(ip))
+    }
+
+    fn register(&mut self, ip: IpAddr) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        *self.per_ip.entry(ip).or_insert(0) += 1;
+        self.sessions.insert(
+            id,
+            Session {
+                ip,
+                connected_at: Instant::now(),
+            },
+        );
+        id
+    }
+
+    pub fn unregister(&mut self, id: u64) {
+        if let Some(session) = self.sessions.remove(&id) {
+            let ip = session.ip;
+            if let Some(count) = self.per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    self.per_ip.remove(&ip);
+                }
+            }
+            log::debug!(
+                "Session {} from {} closed after {:?}",
+                id,
+                session.ip,
+                session.connected_at.elapsed()
+            );
+            self.moved.remove(&id);
+            self.gone.insert(id);
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Addresses with the most open sessions, busiest first.
+    pub fn busiest(&self, limit: usize) -> Vec<(IpAddr, usize)> {
+        let mut busiest: Vec<(IpAddr, usize)> = self
+            .per_ip
+            .iter()
+            .map(|(ip, count)| (*ip, *count))
+            .collect();
+        busiest.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        busiest.truncate(limit);
+        busiest
+    }
+
+    pub fn addresses(&self) -> usize {
+        self.per_ip.len()
+    }
+
+    pub fn move_cursor(&mut self, session: u64, x: u32, y: u32) {
+        if self.sessions.contains_key(&session) {
+            self.moved.insert(session, Cursor { session, x, y });
+        }
+    }
+
+    /// Cursors moved and sessions closed since the previous call.
+    fn take_cursors(&mut self) -> Option<Cursors> {
+        if self.moved.is_empty() && self.gone.is_empty() {
+            return None;
+        }
+        let mut cursors: Vec<Cursor> = self.moved.drain().map(|(_, cursor)| cursor).collect();
+        cursors.sort_unstable_by_key(|cursor| cursor.session);
+        let mut gone: Vec<u64> = self.gone.drain().collect();
+        gone.sort_unstable();
+        Some(Cursors { cursors, gone })
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Cursors {
+    pub cursors: Vec<Cursor>,
+    pub gone: Vec<u64>,
+}
+
+pub async fn presence_timer(state: AppState, config: PresenceConfig) {
+    let mut online_interval = time::interval(config.online_interval);
+    let mut cursor_interval = time::interval(config.cursor_interval);
+    let mut last_count = None;
+    loop {
+        tokio::select! {
+            _ = online_interval.tick() => {
+                let count = state.connections.lock().await.count();
+                if last_count == Some(count) {
+                    continue;
+                }
+                last_count = Some(count);
+                let _ = state.presence.lock().await.send(Event::online(count));
+            }
+            _ = cursor_interval.tick() => {
+                let cursors = state.connections.lock().await.take_cursors();
+                if !config.cursors {
+                    continue;
+                }
+                if let Some(cursors) = cursors {
+                    let _ = state.presence.lock().await.send(Event::cursors(&cursors));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unlimited() -> ConnectionConfig {
+        ConnectionConfig {
+            max_sessions: None,
+            max_sessions_per_ip: None,
+            max_connects_per_sec: None,
+        }
+    }
+
+    #[test]
+    fn admit_enforces_limits() {
+        let first = IpAddr::from([10, 0, 0, 1]);
+        let second = IpAddr::from([10, 0, 0, 2]);
+        let limits = ConnectionConfig {
+            max_sessions: Some(3),
+            max_sessions_per_ip: Some(2),
+            max_connects_per_sec: Some(3),
+        };
+        let mut connections = Connections::new();
+
+        let a = connections.admit(first, &limits).unwrap();
+        connections.admit(first, &limits).unwrap();
+        assert_eq!(
+            Err(Refusal::TooManyFromAddress),
+            connections.admit(first, &limits)
+        );
+        assert_eq!(Err(Refusal::TooFast), connections.admit(first, &limits));
+
+        connections.admit(second, &limits).unwrap();
+        assert_eq!(Err(Refusal::ServerFull), connections.admit(second, &limits));
+
+        connections.unregister(a);
+        connections.admit(second, &limits).unwrap();
+        assert_eq!(3, connections.count());
+    }
+
+    #[test]
+    fn cursors_are_coalesced_per_session() {
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let mut connections = Connections::new();
+        let first = connections.admit(ip, &unlimited()).unwrap();
+        let second = connections.admit(ip, &unlimited()).unwrap();
+        assert_eq!(2, connections.count());
+
+        connections.move_cursor(first, 1, 1);
+        connections.move_cursor(first, 5, 7);
+        connections.move_cursor(42, 0, 0);
+        connections.unregister(second);
+
+        let cursors = connections.take_cursors().unwrap();
+        assert_eq!(
+            Cursors {
+                cursors: vec![Cursor {
+                    session: first,
+                    x: 5,
+                    y: 7
+                }],
+                gone: vec![second],
+            },
+            cursors
+        );
+        assert_eq!(1, connections.count());
+        assert!(connections.take_cursors().is_none());
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+
+use crate::grid::Canvas;
+
+pub const MAX_BRUSH: u32 = 16;
+
+/// Pixels covered by a stroke through `points` drawn with a round brush
+/// `size` pixels wide, or `None` when the stroke is invalid or would touch
+/// more than `max_pixels` pixels.
+pub fn stroke(
+    points: &[(u32, u32)],
+    size: u32,
+    canvas: Canvas,
+    max_pixels: usize,
+) -> Option<Vec<usize>> {
+    if points.is_empty() || size == 0 || size > MAX_BRUSH {
+        return None;
+    }
+    if points
+        .iter()
+        .any(|&(x, y)| canvas.index(x as usize, y as usize).is_none())
+    {
+        return None;
+    }
+
+    let mut centre = vec![points[0]];
+    for pair in points.windows(2) {
+        line(pair[0], pair[1], &mut centre);
+        // Bound the work before stamping the brush along the line
+        if centre.len() > max_pixels {
+            return None;
+        }
+    }
+
+    let radius = (size as i64 - 1) / 2;
+    let mut pixels = HashSet::new();
+    for (x, y) in centre {
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                if dx * dx + dy * dy > radius * radius + radius {
+                    continue;
+                }
+                let (px, py) = (x as i64 + dx, y as i64 + dy);
+                if px < 0 || py < 0 {
+                    continue;
+                }
+                if let Some(index) = canvas.index(px as usize, py as usize) {
+                    pixels.insert(index);
+                }
+            }
+        }
+        if pixels.len() > max_pixels {
+            return None;
+        }
+    }
+
+    let mut pixels: Vec<usize> = pixels.into_iter().collect();
+    pixels.sort_unstable();
+    Some(pixels)
+}
+
+/// Bresenham's line from `from` to `to`, excluding `from` itself.
+fn line(from: (u32, u32), to: (u32, u32), out: &mut Vec<(u32, u32)>) {
+    let (mut x, mut y) = (from.0 as i64, from.1 as i64);
+    let (x1, y1) = (to.0 as i64, to.1 as i64);
+    let dx = (x1 - x).abs();
+    let dy = -(y1 - y).abs();
+    let sx = if x < x1 { 1 } else { -1 };
+    let sy = if y < y1 { 1 } else { -1 };
+    let mut err = dx + dy;
+
+    while (x, y) != (x1, y1) {
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+        out.push((x as u32, y as u32));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thin_stroke_follows_line() {
+        let pixels = stroke(&[(0, 0), (3, 1)], 1, Canvas::default(), 100).unwrap();
+        assert_eq!(vec![0, 1, 1002, 1003], pixels);
+    }
+
+    #[test]
+    fn brush_is_round_and_clipped() {
+        let pixels = stroke(&[(0, 0)], 3, Canvas::default(), 100).unwrap();
+        assert_eq!(vec![0, 1, 1000, 1001], pixels);
+
+        let pixels = stroke(&[(10, 10)], 3, Canvas::default(), 100).unwrap();
+        assert_eq!(9, pixels.len());
+
+        let small = Canvas {
+            width: 10,
+            height: 5,
+        };
+        let pixels = stroke(&[(9, 4)], 3, small, 100).unwrap();
+        assert_eq!(vec![38, 39, 48, 49], pixels);
+        assert!(stroke(&[(10, 0)], 1, small, 100).is_none());
+    }
+
+    #[test]
+    fn invalid_strokes_are_refused() {
+        assert!(stroke(&[], 1, Canvas::default(), 100).is_none());
+        assert!(stroke(&[(0, 0)], 0, Canvas::default(), 100).is_none());
+        assert!(stroke(&[(0, 0)], MAX_BRUSH + 1, Canvas::default(), 100).is_none());
+        assert!(stroke(&[(1000, 0)], 1, Canvas::default(), 100).is_none());
+        assert!(stroke(&[(0, 0), (999, 0)], 1, Canvas::default(), 100).is_none());
+    }
+}
@@ -0,0 +1,150 @@
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::config::RateLimitConfig;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Why [`RateLimiter::check`] refused a write.
+#[derive(Debug, PartialEq)]
+pub enum Throttled {
+    /// Enough tokens will be back after this long
+    Wait(Duration),
+    /// Costs more than a full bucket, so waiting would not help
+    TooLarge,
+}
+
+/// Token buckets for pixel writes, one per client address.
+pub struct RateLimiter {
+    buckets: HashMap<IpAddr, Bucket>,
+    pruned: Instant,
+}
+
+/// How often full buckets may be dropped once there are many of them.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            buckets: HashMap::new(),
+            pruned: Instant::now(),
+        }
+    }
+
+    /// Takes `cost` tokens, or tells how long until that many are available.
+    pub fn check(
+        &mut self,
+        ip: IpAddr,
+        cost: u32,
+        config: &RateLimitConfig,
+        now: Instant,
+    ) -> Result<(), Throttled> {
+        let Some(burst) = config.burst else {
+            return Ok(());
+        };
+        let burst = burst as f64;
+        let refill = config.refill_per_sec;
+        let cost = cost as f64;
+
+        if self.buckets.len() > 4096 && now - self.pruned >= PRUNE_INTERVAL {
+            self.pruned = now;
+            // Buckets that have filled up again carry no information
+            self.buckets.retain(|_, bucket| {
+                bucket.tokens + (now - bucket.updated).as_secs_f64() * refill < burst
+            });
+        }
+
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = (now - bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            return Ok(());
+        }
+        if cost > burst {
+            return Err(Throttled::TooLarge);
+        }
+        if refill <= 0.0 {
+            return Err(Throttled::Wait(Duration::MAX));
+        }
+        Err(Throttled::Wait(Duration::from_secs_f64(
+            (cost - bucket.tokens) / refill,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let config = RateLimitConfig {
+            burst: Some(3),
+            refill_per_sec: 2.0,
+        };
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let other = IpAddr::from([10, 0, 0, 2]);
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new();
+
+        assert!(limiter.check(ip, 2, &config, start).is_ok());
+        assert!(limiter.check(ip, 1, &config, start).is_ok());
+        assert_eq!(
+            Err(Throttled::Wait(Duration::from_millis(500))),
+            limiter.check(ip, 1, &config, start)
+        );
+        assert!(limiter.check(other, 3, &config, start).is_ok());
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check(ip, 1, &config, later).is_ok());
+        assert_eq!(
+            Err(Throttled::TooLarge),
+            limiter.check(ip, 4, &config, later)
+        );
+    }
+
+    #[test]
+    fn prunes_full_buckets_once_per_interval() {
+        let config = RateLimitConfig {
+            burst: Some(1),
+            refill_per_sec: 1000.0,
+        };
+        let mut limiter = RateLimiter::new();
+        let start = limiter.pruned;
+        for i in 0..=4096u32 {
+            let ip = IpAddr::from(i.to_be_bytes());
+            assert!(limiter.check(ip, 1, &config, start).is_ok());
+        }
+        let refilled = start + Duration::from_millis(500);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        assert!(limiter.check(ip, 1, &config, refilled).is_ok());
+        assert_eq!(4098, limiter.buckets.len());
+
+        let later = start + PRUNE_INTERVAL;
+        assert!(limiter.check(ip, 1, &config, later).is_ok());
+        assert_eq!(1, limiter.buckets.len());
+    }
+
+    #[test]
+    fn unlimited_without_burst() {
+        let config = RateLimitConfig {
+            burst: None,
+            refill_per_sec: 0.0,
+        };
+        let mut limiter = RateLimiter::new();
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        for _ in 0..1000 {
+            assert!(limiter.check(ip, 1000, &config, Instant::now()).is_ok());
+        }
+    }
+}
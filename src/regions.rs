@@ -0,0 +1,233 @@
+use std::{fs, io};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bit_utils::{get_bit, set_bit},
+    grid::{Canvas, Rect},
+    state::AppState,
+};
+
+/// Part of the canvas regular users can't draw on.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Region {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub rect: Rect,
+    /// Protected pixels of the rectangle as base64 packed bits, row by row.
+    /// The whole rectangle when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mask: Option<String>,
+}
+
+impl Region {
+    /// Canvas indices of the protected pixels.
+    fn pixels(&self, canvas: Canvas) -> Result<Vec<usize>, String> {
+        if !self.rect.fits(canvas) {
+            return Err(format!("Region {} is not on the canvas", self.id));
+        }
+        let Some(mask) = &self.mask else {
+            return Ok(self.rect.indices(canvas).collect());
+        };
+        let mask = BASE64_STANDARD
+            .decode(mask)
+            .map_err(|err| format!("Mask of region {}: {}", self.id, err))?;
+        let Rect { width, height, .. } = self.rect;
+        if mask.len() != (width * height).div_ceil(8) {
+            return Err(format!("Mask of region {} doesn't match its size", self.id));
+        }
+        Ok(self
+            .rect
+            .indices(canvas)
+            .enumerate()
+            .filter(|(i, _)| get_bit(mask[i / 8], i % 8))
+            .map(|(_, index)| index)
+            .collect())
+    }
+}
+
+/// Mask of the opaque pixels of an image, as stored in [`Region::mask`].
+pub fn opaque_mask(image: &RgbaImage) -> String {
+    let mut mask = vec![0u8; (image.width() * image.height()).div_ceil(8) as usize];
+    for (i, pixel) in image.pixels().enumerate() {
+        if pixel[3] >= 128 {
+            mask[i / 8] = set_bit(mask[i / 8], i % 8, true);
+        }
+    }
+    BASE64_STANDARD.encode(mask)
+}
+
+/// Protected regions together with the pixels they cover.
+pub struct Regions {
+    canvas: Canvas,
+    list: Vec<Region>,
+    /// One bit per canvas pixel
+    covered: Vec<u8>,
+}
+
+impl Regions {
+    pub fn new(canvas: Canvas) -> Self {
+        Self {
+            canvas,
+            list: vec![],
+            covered: vec![0; canvas.pixels().div_ceil(8)],
+        }
+    }
+
+    /// Checks every region and its mask against the canvas.
+    pub fn from_list(canvas: Canvas, list: Vec<Region>) -> Result<Self, String> {
+        let mut regions = Self::new(canvas);
+        for region in list {
+            regions.cover(&region)?;
+            regions.list.push(region);
+        }
+        Ok(regions)
+    }
+
+    pub fn list(&self) -> &[Region] {
+        &self.list
+    }
+
+    pub fn covers(&self, index: usize) -> bool {
+        self.covered
+            .get(index / 8)
+            .is_some_and(|byte| get_bit(*byte, index % 8))
+    }
+
+    /// Protects a rectangle, or the pixels of it set in `mask`.
+    pub fn add(
+        &mut self,
+        name: Option<String>,
+        rect: Rect,
+        mask: Option<String>,
+    ) -> Result<Region, String> {
+        let region = Region {
+            id: self.list.iter().map(|region| region.id).max().unwrap_or(0) + 1,
+            name,
+            rect,
+            mask,
+        };
+        self.cover(&region)?;
+        self.list.push(region.clone());
+        Ok(region)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Region> {
+        let position = self.list.iter().position(|region| region.id == id)?;
+        let region = self.list.remove(position);
+        self.covered.fill(0);
+        let list = std::mem::take(&mut self.list);
+        for region in &list {
+            // Every remaining region was checked when it was added
+            let _ = self.cover(region);
+        }
+        self.list = list;
+        Some(region)
+    }
+
+    /// Puts back a region taken out by [`Regions::remove`].
+    pub fn restore(&mut self, region: Region) {
+        // It was checked when it was first added
+        let _ = self.cover(&region);
+        self.list.push(region);
+        self.list.sort_unstable_by_key(|region| region.id);
+    }
+
+    fn cover(&mut self, region: &Region) -> Result<(), String> {
+        for index in region.pixels(self.canvas)? {
+            self.covered[index / 8] = set_bit(self.covered[index / 8], index % 8, true);
+        }
+        Ok(())
+    }
+}
+
+/// Reads regions saved by [`write`], none when the file doesn't exist yet.
+pub fn read(path: &str, canvas: Canvas) -> io::Result<Regions> {
+    let list = match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+        Err(err) => return Err(err),
+    };
+    Regions::from_list(canvas, list).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub fn write(regions: &Regions, path: &str) -> io::Result<()> {
+    fs::write(path, serde_json::to_vec_pretty(&regions.list)?)
+}
+
+impl AppState {
+    /// Replaces the regions with the ones saved in the regions file.
+    pub async fn load_regions(&self) -> io::Result<usize> {
+        let regions = read(&self.config.storage.regions_path, self.config.canvas)?;
+        let count = regions.list().len();
+        *self.regions.write().await = regions;
+        Ok(count)
+    }
+
+    /// Writes the regions file, called after every change to the regions.
+    pub fn save_regions(&self, regions: &Regions) -> io::Result<()> {
+        write(regions, &self.config.storage.regions_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    const CANVAS: Canvas = Canvas {
+        width: 10,
+        height: 5,
+    };
+
+    fn rect(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn rects_and_masks_cover_their_pixels() {
+        let mut regions = Regions::new(CANVAS);
+        let first = regions.add(None, rect(0, 0, 2, 2), None).unwrap();
+        assert!(regions.covers(0) && regions.covers(11));
+        assert!(!regions.covers(2));
+
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(1, 0, Rgba([0, 0, 0, 255]));
+        let mask = opaque_mask(&image);
+        let second = regions
+            .add(Some("logo".into()), rect(5, 4, 2, 1), Some(mask))
+            .unwrap();
+        assert_eq!(2, second.id);
+        assert!(!regions.covers(45));
+        assert!(regions.covers(46));
+
+        assert_eq!(Some(first), regions.remove(1));
+        assert!(!regions.covers(0));
+        assert!(regions.covers(46));
+        assert_eq!(None, regions.remove(1));
+    }
+
+    #[test]
+    fn bad_regions_are_refused() {
+        let mut regions = Regions::new(CANVAS);
+        assert!(regions.add(None, rect(9, 0, 2, 1), None).is_err());
+        assert!(regions
+            .add(None, rect(0, 0, 4, 4), Some(BASE64_STANDARD.encode([0xff])))
+            .is_err());
+        assert!(regions.list().is_empty());
+
+        let saved = r#"[{"id": 3, "x": 0, "y": 0, "width": 1, "height": 1, "mask": "?"}]"#;
+        let list = serde_json::from_str(saved).unwrap();
+        assert!(Regions::from_list(CANVAS, list).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+#[cfg(unix)]
+use crate::{config::Cli, listener::Tls};
+use crate::{
+    config::{self, Change, Config},
+    state::AppState,
+};
+
+impl AppState {
+    /// Puts the settings of `config` that can change at runtime into effect
+    /// and returns every setting that differs from the current ones.
+    pub fn reconfigure(&self, config: &Config) -> Vec<Change> {
+        let current = self.config.with_live(&self.live.borrow());
+        let changes = config::changes(&current, config);
+        if changes.iter().any(Change::is_live) {
+            self.live.send_replace(config.live());
+        }
+        changes
+    }
+}
+
+/// Reads the config again on every SIGHUP, together with the protected
+/// regions and the TLS certificate, without dropping any connection.
+#[cfg(unix)]
+pub async fn reload_on_hangup(cli: Cli, state: AppState, tls: Option<Tls>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to install signal handler");
+    while hangup.recv().await.is_some() {
+        reload_config(&cli, &state);
+        match state.load_regions().await {
+            Ok(count) => log::info!("Loaded {} protected regions", count),
+            Err(err) => log::error!("Keeping the current protected regions. {}", err),
+        }
+        if let Some(tls) = &tls {
+            match tls.reload().await {
+                Ok(()) => log::info!("Reloaded TLS certificate"),
+                Err(err) => log::error!("Failed to reload TLS certificate: {}", err),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn reload_config(cli: &Cli, state: &AppState) {
+    let config = match cli.load() {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("Keeping the current settings. {}", err);
+            return;
+        }
+    };
+    let changes = state.reconfigure(&config);
+    if changes.is_empty() {
+        log::info!("Reloaded config, nothing changed");
+    }
+    for change in changes {
+        if change.is_live() {
+            log::info!("Changed {}", change);
+        } else {
+            log::warn!("Not changing {} until a restart", change);
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::{collections::HashMap, fs, io};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{self, AuditLog, Entry, Filter},
+    bit_utils::set_bit,
+    config::{Config, RollbackArgs},
+    grid::{Canvas, Grid, MAX_SIZE},
+    state::{differing, read_dump, AppState},
+    tiles::{self, Window},
+    write::Applied,
+};
+
+/// A pixel one client changed, and what it was before they did.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Undo {
+    pub index: usize,
+    pub restore: bool,
+    /// What the client left it at, under this epoch and sequence number
+    pub value: bool,
+    pub epoch: u64,
+    pub seq: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Track {
+    undo: Undo,
+    /// Changed by someone else since
+    overwritten: bool,
+}
+
+/// Works out from the audit log which changes of the filtered client can
+/// be taken back. Feed it every entry, oldest first.
+pub struct Plan {
+    canvas: Canvas,
+    filter: Filter,
+    pixels: HashMap<usize, Track>,
+}
+
+impl Plan {
+    pub fn new(canvas: Canvas, filter: Filter) -> Self {
+        Self {
+            canvas,
+            filter,
+            pixels: HashMap::new(),
+        }
+    }
+
+    pub fn see(&mut self, entry: Entry) {
+        let Some(index) = self.canvas.index(entry.x, entry.y) else {
+            return;
+        };
+        if self.filter.matches(&entry) {
+            let track = self.pixels.entry(index).or_insert(Track {
+                undo: Undo {
+                    index,
+                    // Only real changes are logged, so it was the opposite
+                    restore: !entry.value,
+                    value: entry.value,
+                    epoch: entry.epoch,
+                    seq: entry.seq,
+                },
+                overwritten: false,
+            });
+            if track.overwritten {
+                // Taking it back now means going back to the other write
+                track.undo.restore = !entry.value;
+            }
+            track.undo.value = entry.value;
+            track.undo.epoch = entry.epoch;
+            track.undo.seq = entry.seq;
+            track.overwritten = false;
+        } else if let Some(track) = self.pixels.get_mut(&index) {
+            track.overwritten = true;
+        }
+    }
+
+    /// Pixels to restore, and how many were left alone because someone
+    /// changed them since.
+    pub fn finish(self) -> (Vec<Undo>, usize) {
+        let mut undos = vec![];
+        let mut overwritten = 0;
+        for track in self.pixels.into_values() {
+            if track.overwritten {
+                overwritten += 1;
+            } else if track.undo.restore != track.undo.value {
+                undos.push(track.undo);
+            }
+        }
+        undos.sort_unstable_by_key(|undo| undo.index);
+        (undos, overwritten)
+    }
+}
+
+/// Outcome of taking a client's changes back.
+#[derive(Debug, Serialize)]
+pub struct Reverted {
+    /// Sequence number of the restoring batch, unset when nothing changed
+    pub seq: Option<u64>,
+    pub changed: usize,
+    /// Pixels someone else changed since, left as they are
+    pub skipped: usize,
+}
+
+impl AppState {
+    /// Restores every pixel the filtered client changed to what it was
+    /// before, unless someone else changed it since. The restored pixels are
+    /// broadcast like any other write.
+    pub async fn revert(&self, filter: Filter) -> io::Result<Reverted> {
+        let (undos, skipped) = self.plan_revert(filter).await?;
+        Ok(self.apply_revert(undos, skipped).await)
+    }
+
+    /// Reads the log for what [`AppState::revert`] would restore.
+    async fn plan_revert(&self, filter: Filter) -> io::Result<(Vec<Undo>, usize)> {
+        self.audit.sync().await;
+        let files = self.config.audit.files();
+        let canvas = self.config.canvas;
+        tokio::task::spawn_blocking(move || {
+            let mut plan = Plan::new(canvas, filter);
+            audit::scan(&files, |entry| plan.see(entry))?;
+            Ok::<_, io::Error>(plan.finish())
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    async fn apply_revert(&self, undos: Vec<Undo>, mut skipped: usize) -> Reverted {
+        let mut grid = self.grid.write().await;
+        let modified = self.modified.read().await;
+        let mut restore = Vec::with_capacity(undos.len());
+        for undo in undos {
+            // Writes after the log was read, or missing from it, show here
+            let untouched = match modified.get(&undo.index) {
+                Some(modified) => undo.epoch == self.epoch && undo.seq == modified.seq,
+                None => undo.epoch != self.epoch,
+            };
+            if untouched && grid.read_item(undo.index).await == undo.value {
+                restore.push((undo.index, undo.restore));
+            } else {
+                skipped += 1;
+            }
+        }
+        drop(modified);
+        let applied = self.write_all(&mut grid, restore).await;
+        Reverted {
+            seq: applied.seq,
+            changed: applied.changed,
+            skipped,
+        }
+    }
+}
+
+/// Point in the board's past.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    /// Unix time in milliseconds
+    At(u64),
+    Seq {
+        epoch: u64,
+        seq: u64,
+    },
+}
+
+impl Target {
+    /// Whether the logged change came after this point.
+    fn after(self, entry: &Entry) -> bool {
+        match self {
+            Target::At(at) => entry.at > at,
+            Target::Seq { epoch, seq } => (entry.epoch, entry.seq) > (epoch, seq),
+        }
+    }
+}
+
+/// A [`Target`] as given in a request, either `at` or `seq`.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct Point {
+    pub at: Option<u64>,
+    pub seq: Option<u64>,
+    /// Run the sequence number belongs to
+    pub epoch: Option<u64>,
+}
+
+impl Point {
+    /// The target, with `seq` taken from the `epoch` run unless given.
+    pub fn target(self, epoch: u64) -> Result<Target, String> {
+        match (self.at, self.seq) {
+            (Some(at), None) => Ok(Target::At(at)),
+            (None, Some(seq)) => Ok(Target::Seq {
+                epoch: self.epoch.unwrap_or(epoch),
+                seq,
+            }),
+            _ => Err("Need either at or seq".to_owned()),
+        }
+    }
+}
+
+/// Takes `board` back to `target` by undoing every logged change after it,
+/// each pixel getting the value it had before its first later change.
+/// Returns how many pixels were touched since.
+pub fn rewind(
+    board: &mut [u8; MAX_SIZE],
+    canvas: Canvas,
+    files: &[String],
+    truncated: bool,
+    target: Target,
+) -> io::Result<usize> {
+    let mut undone = vec![false; canvas.pixels()];
+    let mut count = 0;
+    let mut reaches = None;
+    audit::scan(files, |entry| {
+        let after = target.after(&entry);
+        reaches.get_or_insert(!after || !truncated);
+        let Some(index) = canvas.index(entry.x, entry.y).filter(|_| after) else {
+            return;
+        };
+        if !undone[index] {
+            undone[index] = true;
+            count += 1;
+            // Only real changes are logged, so it was the opposite
+            board[index / 8] = set_bit(board[index / 8], index % 8, !entry.value);
+        }
+    })?;
+    if reaches == Some(false) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The audit log doesn't reach back that far",
+        ));
+    }
+    Ok(count)
+}
+
+impl AppState {
+    /// The board as it was at `target`, boxed to keep it off the stack of
+    /// the request futures.
+    pub async fn board_at(&self, target: Target) -> io::Result<Box<[u8; MAX_SIZE]>> {
+        self.audit.sync().await;
+        let (files, truncated) = (self.config.audit.files(), self.config.audit.truncated());
+        let mut board = Box::new(self.grid.read().await.get_full().await);
+        let canvas = self.config.canvas;
+        tokio::task::spawn_blocking(move || {
+            rewind(&mut board, canvas, &files, truncated, target)?;
+            Ok(board)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    /// Brings the whole board back to `target`. Clients get the new board
+    /// in one snapshot.
+    pub async fn roll_back(&self, target: Target) -> io::Result<Applied> {
+        let board = self.board_at(target).await?;
+        Ok(self.resync_board(&board).await)
+    }
+}
+
+/// Rolls the dump back while the server is stopped, or writes a preview.
+pub fn run(config: &Config, args: &RollbackArgs) -> Result<(), String> {
+    let files = config.audit.files();
+    let point = Point {
+        at: args.at,
+        seq: args.seq,
+        epoch: args.epoch,
+    };
+    let last_epoch = audit::last_epoch(&files).map_err(|err| err.to_string())?;
+    // Without an epoch the sequence number is from the last run
+    let target = point.target(last_epoch.unwrap_or(0))?;
+
+    let path = &config.storage.dump_path;
+    let current = read_dump(path).map_err(|err| format!("Can't read {}: {}", path, err))?;
+    let mut board = current;
+    let touched = rewind(
+        &mut board,
+        config.canvas,
+        &files,
+        config.audit.truncated(),
+        target,
+    )
+    .map_err(|err| err.to_string())?;
+    let changes = differing(&current, &board, config.canvas);
+    println!(
+        "{} pixels changed since, {} differ from the dump",
+        touched,
+        changes.len()
+    );
+
+    let canvas = config.canvas;
+    let render = |board: &[u8; MAX_SIZE], png: &str| {
+        tiles::render(board, canvas, config.colors, Window::full(canvas))
+            .save(png)
+            .map_err(|err| format!("Can't write {}: {}", png, err))
+    };
+    if let Some(preview) = &args.preview {
+        render(&board, preview)?;
+        println!("Wrote the preview to {}, the dump is unchanged", preview);
+        return Ok(());
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    // Logged like an admin edit so later rollbacks see it, as a run of its
+    // own that the next server start picks its epoch after
+    let epoch = audit::epoch_after(last_epoch);
+    let mut log = AuditLog::new(config.audit.clone());
+    log.append(&audit::entries(&changes, canvas, epoch, 1, None))
+        .and_then(|()| log.flush())
+        .map_err(|err| format!("Can't write the audit log: {}", err))?;
+    fs::write(path, BASE64_STANDARD.encode(board))
+        .map_err(|err| format!("Can't write {}: {}", path, err))?;
+    render(&board, &config.storage.bitmap_path)?;
+    println!("Rolled {} back", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+    use crate::{audit::Transport, client::Client};
+
+    fn entry(seq: u64, x: usize, value: bool, ip: [u8; 4]) -> Entry {
+        Entry {
+            at: seq * 1000,
+            epoch: 1,
+            seq,
+            x,
+            y: 0,
+            value,
+            ip: Some(IpAddr::from(ip)),
+            session: None,
+            transport: Transport::Http,
+        }
+    }
+
+    #[test]
+    fn plan_skips_pixels_changed_by_others() {
+        const BOT: [u8; 4] = [10, 0, 0, 66];
+        const OTHER: [u8; 4] = [10, 0, 0, 1];
+        let canvas = Canvas {
+            width: 10,
+            height: 1,
+        };
+        let mut plan = Plan::new(
+            canvas,
+            Filter {
+                ip: Some(IpAddr::from(BOT)),
+                since: Some(2000),
+                ..Filter::default()
+            },
+        );
+        for entry in [
+            // Before the window
+            entry(1, 0, true, BOT),
+            entry(2, 0, false, BOT),
+            entry(3, 1, true, BOT),
+            entry(4, 1, false, OTHER),
+            entry(5, 2, true, BOT),
+            entry(6, 2, false, BOT),
+            entry(7, 3, false, OTHER),
+            entry(8, 3, true, BOT),
+        ] {
+            plan.see(entry);
+        }
+        let (undos, overwritten) = plan.finish();
+        let restored: Vec<(usize, bool)> = undos
+            .iter()
+            .map(|undo| (undo.index, undo.restore))
+            .collect();
+        // Pixel 2 ended where it started
+        assert_eq!(vec![(0, true), (3, false)], restored);
+        assert_eq!(1, overwritten);
+        assert_eq!(8, undos[1].seq);
+    }
+
+    #[test]
+    fn plan_restores_the_write_it_overwrote() {
+        const BOT: [u8; 4] = [10, 0, 0, 66];
+        const OTHER: [u8; 4] = [10, 0, 0, 1];
+        let canvas = Canvas {
+            width: 10,
+            height: 1,
+        };
+        let mut plan = Plan::new(
+            canvas,
+            Filter {
+                ip: Some(IpAddr::from(BOT)),
+                ..Filter::default()
+            },
+        );
+        for entry in [
+            entry(1, 0, true, BOT),
+            entry(2, 0, false, OTHER),
+            entry(3, 0, true, OTHER),
+            entry(4, 0, false, BOT),
+        ] {
+            plan.see(entry);
+        }
+        let (undos, overwritten) = plan.finish();
+        assert_eq!(0, overwritten);
+        assert_eq!(1, undos.len());
+        assert!(undos[0].restore);
+        assert!(!undos[0].value);
+    }
+
+    #[tokio::test]
+    async fn revert_leaves_pixels_changed_since_the_log_was_read() {
+        let state = AppState::new(Config::scratch("revert"));
+        let bot = Client::http(IpAddr::from([10, 0, 0, 66]));
+        let other = Client::http(IpAddr::from([10, 0, 0, 1]));
+        for index in 0..3 {
+            assert_eq!(Ok(true), state.toggle(bot, index).await);
+        }
+        assert_eq!(Ok(false), state.toggle(other, 1).await);
+
+        let filter = Filter {
+            ip: Some(bot.ip),
+            ..Filter::default()
+        };
+        let (undos, skipped) = state.plan_revert(filter).await.unwrap();
+        assert_eq!(
+            vec![0, 2],
+            undos.iter().map(|undo| undo.index).collect::<Vec<_>>()
+        );
+        assert_eq!(1, skipped);
+        // Changed and changed back, still not the bot's pixel anymore
+        assert_eq!(Ok(false), state.toggle(other, 2).await);
+        assert_eq!(Ok(true), state.toggle(other, 2).await);
+
+        let reverted = state.apply_revert(undos, skipped).await;
+        assert_eq!(1, reverted.changed);
+        assert_eq!(2, reverted.skipped);
+        assert!(!state.pixel(0).await.0);
+        assert!(!state.pixel(1).await.0);
+        assert!(state.pixel(2).await.0);
+    }
+
+    #[test]
+    fn rewind_undoes_changes_after_target() {
+        let dir = std::env::temp_dir().join(format!("blobgrid-rewind-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.ndjson").to_string_lossy().into_owned();
+        let config = crate::config::AuditConfig {
+            path,
+            ..Default::default()
+        };
+        let mut log = AuditLog::new(config.clone());
+        const CLIENT: [u8; 4] = [10, 0, 0, 1];
+        for entry in [
+            entry(1, 0, true, CLIENT),
+            entry(2, 1, true, CLIENT),
+            entry(3, 0, false, CLIENT),
+            entry(4, 1, false, CLIENT),
+            entry(5, 1, true, CLIENT),
+        ] {
+            log.append(&[entry]).unwrap();
+        }
+        log.flush().unwrap();
+        let files = config.files();
+        let canvas = Canvas {
+            width: 10,
+            height: 1,
+        };
+        let mut board = [0u8; MAX_SIZE];
+        board[0] = 0b10;
+
+        let at_seq = |seq| Target::Seq { epoch: 1, seq };
+        let mut rewound = board;
+        assert_eq!(
+            2,
+            rewind(&mut rewound, canvas, &files, false, at_seq(2)).unwrap()
+        );
+        assert_eq!(0b11, rewound[0]);
+        let mut rewound = board;
+        rewind(&mut rewound, canvas, &files, false, Target::At(0)).unwrap();
+        assert_eq!(0, rewound[0]);
+        // Nothing older is left to know the board from
+        assert!(rewind(&mut board, canvas, &files, true, Target::At(0)).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
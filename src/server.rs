@@ -1,57 +1,138 @@
-use std::{collections::HashSet, fs, sync::Arc, time::Duration};
+use std::time::UNIX_EPOCH;
 
 use axum::{
-    extract::{ws::Message, Path, State},
-    response::{Html, IntoResponse},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use base64::{prelude::BASE64_STANDARD, Engine};
-use rand::{thread_rng, Rng};
-use serde::Serialize;
-use tokio::{
-    sync::{broadcast, Mutex, RwLock},
-    time,
-};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use tower_http::compression::CompressionLayer;
 
 use crate::{
-    fine_grained::Grid2,
+    admin, assets,
+    broadcast::{millis, Batch, Event},
+    client::Client,
+    encoding::{BoardEncoding, ContentCoding},
     grid::{Grid, SubRectInfo},
+    sse,
     state::AppState,
+    write::{pixel_index, Applied, Op, PixelWrite, Transaction, WriteError},
     ws,
 };
 
-/*
-async fn get_grid(
-    Path((from_index, to_index)): Path<(usize, usize)>,
-    State(state): State<AppState>,
-) -> impl IntoResponse {
-    let grid = state.grid.read().await;
-    let item = grid.get_item(from_index);
-    if let Some(item) = item {
-        item.to_string()
-    } else {
-        "None".to_owned()
+#[derive(Serialize)]
+struct PixelJson {
+    x: usize,
+    y: usize,
+    value: bool,
+    /// Sequence number of the last change, unknown for pixels untouched
+    /// since startup
+    seq: Option<u64>,
+    /// Unix time of the last change in milliseconds
+    last_modified: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct PixelValue {
+    value: bool,
+}
+
+async fn pixel_json(state: &AppState, x: usize, y: usize, index: usize) -> PixelJson {
+    let (value, modified) = state.pixel(index).await;
+    PixelJson {
+        x,
+        y,
+        value,
+        seq: modified.map(|modified| modified.seq),
+        last_modified: modified.and_then(|modified| {
+            let since_epoch = modified.at.duration_since(UNIX_EPOCH).ok()?;
+            Some(millis(since_epoch))
+        }),
     }
 }
-*/
+
+async fn get_pixel(
+    Path((x, y)): Path<(usize, usize)>,
+    State(state): State<AppState>,
+) -> Result<Json<PixelJson>, WriteError> {
+    let index = pixel_index(state.config.canvas, x, y)?;
+    Ok(Json(pixel_json(&state, x, y, index).await))
+}
+
+async fn put_pixel(
+    Path((x, y)): Path<(usize, usize)>,
+    client: Client,
+    State(state): State<AppState>,
+    Json(PixelValue { value }): Json<PixelValue>,
+) -> Result<Json<PixelJson>, WriteError> {
+    let index = pixel_index(state.config.canvas, x, y)?;
+    let write = PixelWrite {
+        index,
+        op: Op::Set(value),
+        expect: None,
+    };
+    state.apply(client, &[write]).await?;
+    Ok(Json(pixel_json(&state, x, y, index).await))
+}
 
 async fn set_checkbox(
     Path(index): Path<usize>,
+    client: Client,
     State(state): State<AppState>,
-) -> impl IntoResponse {
-    let toggled = state.toggle(index).await;
+) -> Result<&'static str, WriteError> {
+    if !state.config.canvas.contains(index) {
+        return Err(WriteError::OutOfRange);
+    }
+    let toggled = state.toggle(client, index).await?;
 
     if toggled {
-        "1"
+        Ok("1")
     } else {
-        "0"
+        Ok("0")
+    }
+}
+
+async fn transaction(
+    client: Client,
+    State(state): State<AppState>,
+    Json(transaction): Json<Transaction>,
+) -> Result<Json<Applied>, WriteError> {
+    let writes = transaction.into_writes(state.config.canvas, state.config.max_batch)?;
+    Ok(Json(state.apply(client, &writes).await?))
+}
+
+impl IntoResponse for WriteError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            WriteError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            WriteError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            WriteError::InvalidBatch | WriteError::InvalidStroke => StatusCode::BAD_REQUEST,
+            WriteError::Conflict { .. } => StatusCode::CONFLICT,
+            WriteError::OutOfRange => StatusCode::NOT_FOUND,
+            WriteError::Banned | WriteError::Protected { .. } => StatusCode::FORBIDDEN,
+            WriteError::ReadOnly => StatusCode::LOCKED,
+            WriteError::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        let body = Event::rejected(&self).data.to_string();
+        let mut response =
+            (status, [(header::CONTENT_TYPE, "application/json")], body).into_response();
+        if let Some(retry_after) = self.retry_after() {
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
     }
 }
 
+#[cfg(not(feature = "embed"))]
 async fn index() -> impl IntoResponse {
-    Html(
+    axum::response::Html(
         r#"""
     <html>
         <head><title>Grid shit</title></head>
@@ -63,18 +144,156 @@ async fn index() -> impl IntoResponse {
     )
 }
 
-async fn full_grid(State(state): State<AppState>) -> impl IntoResponse {
-    let grid = state.grid.read().await;
-    let full = grid.get_full().await;
+async fn full_grid(headers: HeaderMap, State(state): State<AppState>) -> Response {
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    let encoding = BoardEncoding::negotiate(accept);
+    let accept_encoding = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok());
+    let coding = ContentCoding::negotiate(accept_encoding);
+
+    let encoded = state.encoded_snapshot(encoding, coding).await;
+    // The sequence number only moves forward, so a matching tag is current
+    let etag = board_etag(state.epoch, encoded.seq, encoding, coding);
+    if etag_matches(&headers, &etag) {
+        return (
+            StatusCode::NOT_MODIFIED,
+            [
+                (header::ETAG, etag),
+                (header::VARY, "accept, accept-encoding".to_owned()),
+            ],
+        )
+            .into_response();
+    }
 
-    BASE64_STANDARD.encode(full)
+    let mut response = (
+        [
+            (header::CONTENT_TYPE, encoding.content_type().to_owned()),
+            (header::ETAG, etag),
+            (header::VARY, "accept, accept-encoding".to_owned()),
+            (header::CACHE_CONTROL, "no-cache".to_owned()),
+        ],
+        encoded.body.clone(),
+    )
+        .into_response();
+    // Already compressed, so the compression layer leaves it alone
+    if let Some(content_encoding) = coding.header() {
+        response.headers_mut().insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(content_encoding),
+        );
+    }
+    response
+}
+
+fn board_etag(epoch: u64, seq: u64, encoding: BoardEncoding, coding: ContentCoding) -> String {
+    format!(
+        "\"{:x}-{}-{}-{}\"",
+        epoch,
+        seq,
+        encoding.tag(),
+        coding.tag()
+    )
+}
+
+pub fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == etag || tag.strip_prefix("W/") == Some(etag))
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    since: u64,
+    /// Run the `since` sequence number belongs to
+    epoch: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Changes {
+    epoch: u64,
+    #[serde(flatten)]
+    batch: Batch,
+}
+
+#[derive(Serialize)]
+struct FetchSnapshot {
+    reason: &'static str,
+    epoch: u64,
+    seq: u64,
+}
+
+/// Pixels changed after `since` with their final values, or 410 when the
+/// client has to start over from `/api/grid`.
+async fn changes(Query(query): Query<ChangesQuery>, State(state): State<AppState>) -> Response {
+    let history = state.history.read().await;
+    let same_run = query.epoch.is_none_or(|epoch| epoch == state.epoch);
+    match history.changes_since(query.since).filter(|_| same_run) {
+        Some(batch) => Json(Changes {
+            epoch: state.epoch,
+            batch,
+        })
+        .into_response(),
+        None => (
+            StatusCode::GONE,
+            Json(FetchSnapshot {
+                reason: "fetch_snapshot",
+                epoch: state.epoch,
+                seq: history.last_seq(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+async fn tile(
+    Path((z, x, y)): Path<(u32, usize, String)>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    let Some(y) = y.strip_suffix(".png").and_then(|y| y.parse().ok()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let tile = match state.tile(z, x, y).await {
+        Ok(Some(tile)) => tile,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            log::error!("Failed to render tile {}/{}/{}: {}", z, x, y, err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let etag = format!("\"{:x}-{}\"", state.epoch, tile.version);
+    if etag_matches(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+    (
+        [
+            (header::CONTENT_TYPE, "image/png".to_owned()),
+            (header::ETAG, etag),
+            (header::CACHE_CONTROL, "no-cache".to_owned()),
+        ],
+        tile.png.clone(),
+    )
+        .into_response()
 }
 
 async fn sub_grid(State(state): State<AppState>) -> impl IntoResponse {
+    let canvas = state.config.canvas;
+    let bytes_width = (canvas.width / 8).min(10);
+    let height = canvas.height.min(80);
+    let x_shift = rand::thread_rng().gen_range(0..=(canvas.width / 8 - bytes_width));
+    let y_shift = rand::thread_rng().gen_range(0..=(canvas.height - height));
     let grid = state.grid.read().await;
-    let x_shift = rand::thread_rng().gen_range(0..(125 - 10));
-    let y_shift = rand::thread_rng().gen_range(0..(1000 - 80));
-    let subgrid = grid.get_rect(x_shift, y_shift, 10, 80).await;
+    let subgrid = grid
+        .get_rect(canvas, x_shift, y_shift, bytes_width, height)
+        .await;
     let subgrid2 = SubRectInfoJson::from_info(&subgrid);
     Json(subgrid2)
 }
@@ -103,34 +322,91 @@ impl SubRectInfoJson {
 }
 
 pub fn router(state: AppState) -> Router {
-    Router::new()
+    let api = Router::new()
         .route("/ws", get(ws::ws_grid))
         .route("/api/grid", get(full_grid))
+        .route("/api/events", get(sse::events))
+        .route("/api/changes", get(changes))
+        .route("/tiles/:z/:x/:y", get(tile))
         .route("/api/subgrid", get(sub_grid))
         .route("/set/:index", post(set_checkbox))
-        //.route("/grid/:from/:to", get(get_grid))
-        .route("/", get(index))
-        .layer(CompressionLayer::new())
-        .with_state(state)
+        .route("/api/transaction", post(transaction))
+        .route("/api/pixel/:x/:y", get(get_pixel).put(put_pixel))
+        .nest("/admin", admin::router(&state));
+    let app = match &state.config.static_dir {
+        Some(dir) => api.merge(assets::directory(dir)),
+        #[cfg(feature = "embed")]
+        None => api.merge(assets::embedded::router()),
+        #[cfg(not(feature = "embed"))]
+        None => api.route("/", get(index)),
+    };
+    app.layer(CompressionLayer::new()).with_state(state)
 }
 
 #[cfg(test)]
 mod tests {
-    use image::{buffer, GenericImage, GenericImageView};
+    use std::net::IpAddr;
 
+    use image::GenericImageView;
+
+    use super::*;
     use crate::{
         bit_utils::{get_bit, set_bit},
+        config::Config,
         fine_grained::Grid2,
-        grid::{Grid, MAX_SIZE},
+        grid::MAX_SIZE,
         state::PointQueue,
     };
 
+    #[test]
+    fn board_etag_tells_codings_apart() {
+        let gzip = board_etag(7, 3, BoardEncoding::Raw, ContentCoding::Gzip);
+        let brotli = board_etag(7, 3, BoardEncoding::Raw, ContentCoding::Brotli);
+        assert_ne!(gzip, brotli);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, gzip.parse().unwrap());
+        assert!(etag_matches(&headers, &gzip));
+        assert!(!etag_matches(&headers, &brotli));
+    }
+
+    #[tokio::test]
+    async fn put_then_get_pixel() {
+        let state = AppState::new(Config::scratch("pixel"));
+        let client = Client::http(IpAddr::from([127, 0, 0, 1]));
+
+        let Json(put) = put_pixel(
+            Path((3, 2)),
+            client,
+            State(state.clone()),
+            Json(PixelValue { value: true }),
+        )
+        .await
+        .unwrap();
+        assert!(put.value);
+        assert_eq!(Some(1), put.seq);
+        assert!(put.last_modified.is_some());
+
+        let Json(get) = get_pixel(Path((3, 2)), State(state.clone())).await.unwrap();
+        assert_eq!((3, 2, true, Some(1)), (get.x, get.y, get.value, get.seq));
+        assert!(state.pixel(2 * state.config.canvas.width + 3).await.0);
+
+        let Json(untouched) = get_pixel(Path((4, 2)), State(state.clone())).await.unwrap();
+        assert_eq!((false, None), (untouched.value, untouched.seq));
+
+        let width = state.config.canvas.width;
+        assert_eq!(
+            Some(WriteError::OutOfRange),
+            get_pixel(Path((width, 0)), State(state)).await.err()
+        );
+    }
+
     #[test]
     fn json_test() {
         let mut pq = PointQueue::new();
         pq.off.insert(1111);
         pq.on.insert(323123);
-        let result = serde_json::to_string(&pq);
+        let result = serde_json::to_string(&pq).unwrap();
         dbg!(result);
     }
 
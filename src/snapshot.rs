@@ -0,0 +1,113 @@
+use std::{collections::HashMap, sync::Arc};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bytes::Bytes;
+
+use crate::{
+    encoding::{rle_encode, BoardEncoding, ContentCoding},
+    state::AppState,
+};
+
+/// A full board body, encoded and compressed once and shared by every
+/// request until the next batch goes out.
+pub struct Encoded {
+    /// Sequence number the board reflects
+    pub seq: u64,
+    pub body: Bytes,
+}
+
+/// Encoded boards keyed by representation.
+///
+/// An entry stays valid until a batch newer than its board is broadcast,
+/// so the board is copied and encoded about once per batch no matter how
+/// many clients ask for it in between. Requests that miss at the same time
+/// each encode their own copy rather than wait on a lock.
+#[derive(Default)]
+pub struct SnapshotCache {
+    entries: HashMap<(BoardEncoding, ContentCoding), Arc<Encoded>>,
+}
+
+impl SnapshotCache {
+    fn fresh(&self, key: (BoardEncoding, ContentCoding), batch_seq: u64) -> Option<Arc<Encoded>> {
+        self.entries
+            .get(&key)
+            .filter(|encoded| encoded.seq >= batch_seq)
+            .cloned()
+    }
+}
+
+impl AppState {
+    /// The board in the given representation, rebuilt only when a newer
+    /// batch has been broadcast since it was last encoded.
+    pub async fn encoded_snapshot(
+        &self,
+        encoding: BoardEncoding,
+        coding: ContentCoding,
+    ) -> Arc<Encoded> {
+        let key = (encoding, coding);
+        let batch_seq = self.history.read().await.last_seq();
+        if let Some(encoded) = self.snapshots.lock().await.fresh(key, batch_seq) {
+            return encoded;
+        }
+
+        let (seq, board) = self.snapshot().await;
+        let board = board.to_vec();
+        // Compressing takes milliseconds, keep it off the runtime workers
+        let body = tokio::task::spawn_blocking(move || {
+            let data = match encoding {
+                BoardEncoding::Base64 => BASE64_STANDARD.encode(&board).into_bytes(),
+                BoardEncoding::Raw => board,
+                BoardEncoding::Rle => rle_encode(&board),
+            };
+            coding.compress(data)
+        })
+        .await
+        .expect("Encoding the board never panics");
+        let encoded = Arc::new(Encoded {
+            seq,
+            body: body.into(),
+        });
+        log::debug!(
+            "Encoded board at seq {} as {:?}/{:?}, {} bytes",
+            seq,
+            encoding,
+            coding,
+            encoded.body.len()
+        );
+
+        let mut cache = self.snapshots.lock().await;
+        // Another request may have stored a newer board in the meantime
+        match cache.entries.get(&key) {
+            Some(cached) if cached.seq > seq => cached.clone(),
+            _ => {
+                cache.entries.insert(key, encoded.clone());
+                encoded
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_expire_with_newer_batches() {
+        let key = (BoardEncoding::Raw, ContentCoding::Identity);
+        let mut cache = SnapshotCache::default();
+        assert!(cache.fresh(key, 0).is_none());
+
+        cache.entries.insert(
+            key,
+            Arc::new(Encoded {
+                seq: 5,
+                body: Bytes::new(),
+            }),
+        );
+        assert!(cache.fresh(key, 5).is_some());
+        assert!(cache.fresh(key, 6).is_none());
+        assert!(cache
+            .fresh((BoardEncoding::Rle, ContentCoding::Identity), 0)
+            .is_none());
+    }
+}
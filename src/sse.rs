@@ -0,0 +1,149 @@
+use std::{convert::Infallible, sync::atomic::Ordering, time::Duration};
+
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse,
+    },
+};
+use futures::{stream, Stream, StreamExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{broadcast::Event, state::AppState};
+
+pub async fn events(headers: HeaderMap, State(state): State<AppState>) -> impl IntoResponse {
+    let resume = headers.get("last-event-id").map(|value| {
+        value
+            .to_str()
+            .ok()
+            .and_then(|id| resume_seq(state.epoch, id))
+    });
+
+    // Subscribe before looking at the history so nothing slips in between
+    let receiver = state.broadcast.lock().await.subscribe();
+    let backlog = match resume {
+        Some(seq) => catch_up(&state, seq).await,
+        None => vec![],
+    };
+    let last_sent = backlog
+        .last()
+        .and_then(|event| event.id)
+        .or(resume.flatten())
+        .unwrap_or(0);
+
+    let epoch = state.epoch;
+    let notice = state.mode_notice();
+    let live = live_events(state, receiver, last_sent);
+    let events = stream::iter(notice.into_iter().chain(backlog))
+        .chain(live)
+        .map(move |event| to_sse(epoch, event));
+
+    Sse::new(events).keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+}
+
+/// Event ids are `epoch:seq`, so one from another run of the server isn't
+/// taken for a sequence number of this one.
+fn event_id(epoch: u64, seq: u64) -> String {
+    format!("{}:{}", epoch, seq)
+}
+
+/// Sequence number to resume from, if the id is from this run.
+fn resume_seq(epoch: u64, last_event_id: &str) -> Option<u64> {
+    let (id_epoch, seq) = last_event_id.trim().split_once(':')?;
+    (id_epoch.parse() == Ok(epoch)).then_some(seq.parse().ok()?)
+}
+
+/// Events a client needs after `seq` to be in sync again, the whole board
+/// when it isn't known where the client left off.
+async fn catch_up(state: &AppState, seq: Option<u64>) -> Vec<Event> {
+    let replay = match seq {
+        Some(seq) if seq <= state.seq.load(Ordering::SeqCst) => {
+            state.history.read().await.since(seq)
+        }
+        _ => None,
+    };
+    match replay {
+        Some(batches) => batches.iter().map(|batch| Event::batch(batch)).collect(),
+        None => vec![snapshot(state).await],
+    }
+}
+
+async fn snapshot(state: &AppState) -> Event {
+    let (seq, board) = state.snapshot().await;
+    Event::snapshot(seq, &board)
+}
+
+fn live_events(
+    state: AppState,
+    receiver: broadcast::Receiver<Event>,
+    last_sent: u64,
+) -> impl Stream<Item = Event> {
+    stream::unfold(
+        (state, receiver, last_sent),
+        |(state, mut receiver, last_sent)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => {
+                        if event.id.is_some_and(|id| id <= last_sent) {
+                            continue;
+                        }
+                        let last_sent = event.id.unwrap_or(last_sent);
+                        return Some((event, (state, receiver, last_sent)));
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::debug!("SSE client lagged by {} events, resyncing", skipped);
+                        let event = snapshot(&state).await;
+                        let last_sent = event.id.unwrap_or(last_sent);
+                        return Some((event, (state, receiver, last_sent)));
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    )
+}
+
+fn to_sse(epoch: u64, event: Event) -> Result<sse::Event, Infallible> {
+    let mut sse_event = sse::Event::default().event(event.name).data(&*event.data);
+    if let Some(seq) = event.id {
+        sse_event = sse_event.id(event_id(epoch, seq));
+    }
+    Ok(sse_event)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{broadcast::Batch, config::Config};
+
+    #[tokio::test]
+    async fn resumes_only_ids_from_this_run() {
+        let mut config = Config::scratch("sse");
+        config.broadcast.history_size = 10;
+        let state = AppState::new(config);
+        state.seq.store(5, Ordering::SeqCst);
+        state.history.write().await.push(Arc::new(Batch {
+            seq: 5,
+            from: 2,
+            on: vec![1],
+            off: vec![],
+        }));
+
+        let resumed = catch_up(&state, resume_seq(state.epoch, &event_id(state.epoch, 2))).await;
+        assert_eq!(vec![("batch", Some(5))], names(&resumed));
+
+        // An earlier run with a lower sequence number, or an id without epoch
+        for id in [event_id(state.epoch - 1, 2), "2".to_owned()] {
+            let events = catch_up(&state, resume_seq(state.epoch, &id)).await;
+            assert_eq!(vec![("snapshot", Some(5))], names(&events));
+        }
+    }
+
+    fn names(events: &[Event]) -> Vec<(&'static str, Option<u64>)> {
+        events.iter().map(|event| (event.name, event.id)).collect()
+    }
+}
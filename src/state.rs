@@ -1,58 +1,294 @@
-use std::{collections::HashSet, fs, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
 
-use axum::extract::ws::Message;
 use base64::{prelude::BASE64_STANDARD, Engine};
+use image::DynamicImage;
 use serde::Serialize;
 use tokio::{
-    sync::{broadcast, Mutex, RwLock},
-    time,
+    sync::{broadcast, watch, Mutex, RwLock},
+    time::Instant,
 };
 
 use crate::{
+    audit::{self, AuditWriter},
     bit_utils::get_bit,
+    broadcast::{broadcast_timer, Event},
+    client::Client,
+    config::{Config, LiveConfig},
     fine_grained::Grid2,
-    grid::{Grid, MAX_SIZE},
+    grid::{Canvas, Grid, MAX_SIZE},
+    history::History,
+    mode::BoardMode,
+    presence::{presence_timer, Connections},
+    rate_limit::{RateLimiter, Throttled},
+    regions::Regions,
+    snapshot::SnapshotCache,
+    tiles::{self, Tiles, Window},
+    write::{Applied, Op, PixelWrite, WriteError},
 };
 
 #[derive(Clone)]
 pub struct AppState {
     pub dump_path: String,
     pub bitmap_path: String,
+    /// Settings as loaded at startup, see `live` for those a reload changes
+    pub config: Arc<Config>,
+    pub live: watch::Sender<LiveConfig>,
     pub grid: Arc<RwLock<Grid2>>,
-    pub broadcast: Arc<Mutex<broadcast::Sender<Message>>>,
+    pub broadcast: Arc<Mutex<broadcast::Sender<Event>>>,
     pub queue: Arc<Mutex<PointQueue>>,
+    pub history: Arc<RwLock<History>>,
+    /// Sequence number of the last write applied to the grid
+    pub seq: Arc<AtomicU64>,
+    /// Start time of this run, sequence numbers restart with it
+    pub epoch: u64,
+    pub connections: Arc<Mutex<Connections>>,
+    /// Online counts and cursors, kept apart so they never push batches out
+    pub presence: Arc<Mutex<broadcast::Sender<Event>>>,
+    pub rate_limiter: Arc<Mutex<RateLimiter>>,
+    /// Encoded boards served by `/api/grid`
+    pub snapshots: Arc<Mutex<SnapshotCache>>,
+    /// Rendered PNG tiles and the version of every tile
+    pub tiles: Arc<Mutex<Tiles>>,
+    /// When each pixel last changed, for pixels changed since startup
+    pub modified: Arc<RwLock<HashMap<usize, Modified>>>,
+    /// Whether regular clients may write, switched by the operator
+    pub mode: watch::Sender<BoardMode>,
+    /// Parts of the canvas only the operator may change
+    pub regions: Arc<RwLock<Regions>>,
+    /// Who changed which pixel, written with every change
+    pub audit: AuditWriter,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Modified {
+    pub seq: u64,
+    pub at: SystemTime,
 }
 
 impl AppState {
-    pub async fn toggle(&self, index: usize) -> bool {
+    pub async fn toggle(&self, client: Client, index: usize) -> Result<bool, WriteError> {
+        self.admit(client)?;
+        self.check_protected([index]).await?;
+        self.charge(client, 1).await?;
         let mut grid = self.grid.write().await;
         let toggled = grid.toggle_item(index).await;
-        self.push_index(index, toggled).await;
-        log::info!("Got set checkbox to index {} {}", index, toggled);
-        toggled
+        self.record(&[(index, toggled)], Some(client)).await;
+        log::info!("{} toggled pixel {} to {}", client.ip, index, toggled);
+        Ok(toggled)
     }
 
-    async fn push_index(&self, index: usize, toggled: bool) {
-        let mut queue = self.queue.lock().await;
-        if toggled {
-            if queue.on.contains(&index) {
-                return;
-            } else if queue.off.contains(&index) {
-                queue.on.remove(&index);
-                queue.off.remove(&index);
-            } else {
-                queue.on.insert(index);
+    /// Applies a batch of writes under one grid lock. Either every write is
+    /// applied under a single sequence number or, when a precondition fails,
+    /// none of them are.
+    pub async fn apply(
+        &self,
+        client: Client,
+        writes: &[PixelWrite],
+    ) -> Result<Applied, WriteError> {
+        self.admit(client)?;
+        self.check_protected(writes.iter().map(|write| write.index))
+            .await?;
+        let pixels = u32::try_from(writes.len()).unwrap_or(u32::MAX);
+        self.charge(client, pixels).await?;
+
+        let mut grid = self.grid.write().await;
+        let mut conflicts = vec![];
+        for write in writes {
+            if let Some(expect) = write.expect {
+                if grid.read_item(write.index).await != expect {
+                    conflicts.push(write.index);
+                }
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(WriteError::Conflict { pixels: conflicts });
+        }
+
+        let mut changes = Vec::with_capacity(writes.len());
+        for write in writes {
+            match write.op {
+                Op::Toggle => {
+                    changes.push((write.index, grid.toggle_item(write.index).await));
+                }
+                Op::Set(value) => {
+                    if grid.write_item(write.index, value).await {
+                        changes.push((write.index, value));
+                    }
+                }
             }
+        }
+        let seq = self.record(&changes, Some(client)).await;
+        log::info!(
+            "{} applied batch of {} writes, {} changed",
+            client.ip,
+            writes.len(),
+            changes.len()
+        );
+        Ok(Applied {
+            seq,
+            changed: changes.len(),
+        })
+    }
+
+    /// Gives the changes one sequence number, queues them for broadcast and
+    /// logs them for `client`, the operator when `None`. Must be called
+    /// while holding the grid write lock.
+    async fn record(&self, changes: &[(usize, bool)], client: Option<Client>) -> Option<u64> {
+        let seq = self.stamp(changes, client).await?;
+        let mut queue = self.queue.lock().await;
+        queue.seq = seq;
+        for &(index, value) in changes {
+            queue.push(index, value);
+        }
+        Some(seq)
+    }
+
+    /// Like [`AppState::record`] without queueing the changes for broadcast.
+    async fn stamp(&self, changes: &[(usize, bool)], client: Option<Client>) -> Option<u64> {
+        if changes.is_empty() {
+            return None;
+        }
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let at = SystemTime::now();
+        let mut modified = self.modified.write().await;
+        let mut tiles = self.tiles.lock().await;
+        for &(index, _) in changes {
+            modified.insert(index, Modified { seq, at });
+            tiles.touch(index, seq);
+        }
+        let entries = audit::entries(changes, self.config.canvas, self.epoch, seq, client);
+        self.audit.append(entries);
+        Some(seq)
+    }
+
+    /// Current value of a pixel and when it last changed.
+    pub async fn pixel(&self, index: usize) -> (bool, Option<Modified>) {
+        let grid = self.grid.read().await;
+        let value = grid.read_item(index).await;
+        let modified = self.modified.read().await.get(&index).copied();
+        (value, modified)
+    }
+
+    /// Turns away clients that may not write at all.
+    fn admit(&self, client: Client) -> Result<(), WriteError> {
+        if self.live.borrow().is_banned(client.ip) {
+            return Err(WriteError::Banned);
+        }
+        self.mode.borrow().mode.check_write()
+    }
+
+    /// Turns away writes to protected regions.
+    async fn check_protected(
+        &self,
+        indices: impl IntoIterator<Item = usize>,
+    ) -> Result<(), WriteError> {
+        let regions = self.regions.read().await;
+        let pixels: Vec<usize> = indices
+            .into_iter()
+            .filter(|&index| regions.covers(index))
+            .collect();
+        if pixels.is_empty() {
+            Ok(())
         } else {
-            if queue.off.contains(&index) {
-                return;
-            } else if queue.on.contains(&index) {
-                queue.on.remove(&index);
-                queue.off.remove(&index);
-            } else {
-                queue.off.insert(index);
+            Err(WriteError::Protected { pixels })
+        }
+    }
+
+    /// Sets pixels for the operator, past bans, rate limits and the mode.
+    pub async fn overwrite(&self, pixels: impl IntoIterator<Item = (usize, bool)>) -> Applied {
+        let mut grid = self.grid.write().await;
+        self.write_all(&mut grid, pixels).await
+    }
+
+    /// Makes the canvas match `board`, changing only the pixels that differ.
+    pub async fn replace_board(&self, board: &[u8; MAX_SIZE]) -> Applied {
+        let mut grid = self.grid.write().await;
+        let current = grid.get_full().await;
+        let differing = differing(&current, board, self.config.canvas);
+        self.write_all(&mut grid, differing).await
+    }
+
+    /// Makes the canvas match `board` and sends every client the whole board
+    /// instead of the changed pixels. Catching up from before goes through a
+    /// snapshot as well.
+    pub async fn resync_board(&self, board: &[u8; MAX_SIZE]) -> Applied {
+        let mut grid = self.grid.write().await;
+        // Keeps the timer from sending out anything in between
+        let mut queue = self.queue.lock().await;
+        let mut changes = vec![];
+        let differing = differing(&grid.get_full().await, board, self.config.canvas);
+        for (index, value) in differing {
+            grid.write_item(index, value).await;
+            changes.push((index, value));
+        }
+        let Some(seq) = self.stamp(&changes, None).await else {
+            return Applied {
+                seq: None,
+                changed: 0,
+            };
+        };
+        queue.clear();
+        queue.seq = seq;
+        self.history.write().await.restart(seq);
+        // The grid matches `board` now
+        let _ = self
+            .broadcast
+            .lock()
+            .await
+            .send(Event::snapshot(seq, board));
+        log::info!("Resynced {} pixels", changes.len());
+        Applied {
+            seq: Some(seq),
+            changed: changes.len(),
+        }
+    }
+
+    /// Sets pixels as the operator while the caller holds the grid lock.
+    pub async fn write_all(
+        &self,
+        grid: &mut Grid2,
+        pixels: impl IntoIterator<Item = (usize, bool)>,
+    ) -> Applied {
+        let mut changes = vec![];
+        for (index, value) in pixels {
+            if grid.write_item(index, value).await {
+                changes.push((index, value));
             }
         }
+        let seq = self.record(&changes, None).await;
+        log::info!("Overwrote {} pixels", changes.len());
+        Applied {
+            seq,
+            changed: changes.len(),
+        }
+    }
+
+    /// Takes `pixels` writes from the client's allowance.
+    async fn charge(&self, client: Client, pixels: u32) -> Result<(), WriteError> {
+        let rate_limit = self.live.borrow().rate_limit.clone();
+        self.rate_limiter
+            .lock()
+            .await
+            .check(client.ip, pixels, &rate_limit, Instant::now())
+            .map_err(|throttled| match throttled {
+                Throttled::Wait(retry_after) => WriteError::RateLimited { retry_after },
+                Throttled::TooLarge => WriteError::TooLarge,
+            })
+    }
+
+    /// Copy of the board together with the sequence number it reflects.
+    pub async fn snapshot(&self) -> (u64, [u8; MAX_SIZE]) {
+        let grid = self.grid.read().await;
+        let seq = self.seq.load(Ordering::SeqCst);
+        (seq, grid.get_full().await)
     }
 }
 
@@ -60,6 +296,8 @@ impl AppState {
 pub struct PointQueue {
     pub on: HashSet<usize>,
     pub off: HashSet<usize>,
+    /// Sequence number of the latest write in the queue
+    pub seq: u64,
 }
 
 impl PointQueue {
@@ -67,109 +305,137 @@ impl PointQueue {
         Self {
             on: HashSet::new(),
             off: HashSet::new(),
+            seq: 0,
+        }
+    }
+
+    fn push(&mut self, index: usize, value: bool) {
+        // A pixel flipped back before the batch went out cancels itself
+        if value {
+            if !self.off.remove(&index) {
+                self.on.insert(index);
+            }
+        } else if !self.on.remove(&index) {
+            self.off.insert(index);
         }
     }
 
-    fn clear(&mut self) {
+    pub fn len(&self) -> usize {
+        self.on.len() + self.off.len()
+    }
+
+    pub fn clear(&mut self) {
         self.on.clear();
         self.off.clear();
     }
 
-    fn is_empty(&self) -> bool {
+    pub fn is_empty(&self) -> bool {
         self.on.is_empty() && self.off.is_empty()
     }
 }
 
 impl AppState {
-    pub fn new(dump_path: &str, bitmap_path: &str) -> Self {
-        let (tx, _) = broadcast::channel(100);
-
-        let queue = Arc::new(Mutex::new(PointQueue::new()));
-        let broadcast = Arc::new(Mutex::new(tx));
-        tokio::spawn(broadcast_timer(queue.clone(), broadcast.clone()));
+    pub fn new(config: Config) -> Self {
+        let (tx, _) = broadcast::channel(config.broadcast.channel_size);
+        let (presence_tx, _) = broadcast::channel(config.broadcast.channel_size);
+        // After every logged run, CLI rollbacks included
+        let last_epoch = audit::last_epoch(&config.audit.files()).unwrap_or_else(|err| {
+            log::warn!("Can't read the last epoch from the audit log: {}", err);
+            None
+        });
 
         let grid = Grid::new();
-        AppState {
-            dump_path: dump_path.to_owned(),
-            bitmap_path: bitmap_path.to_owned(),
+        let state = AppState {
+            dump_path: config.storage.dump_path.clone(),
+            bitmap_path: config.storage.bitmap_path.clone(),
+            config: Arc::new(config.clone()),
+            live: watch::Sender::new(config.live()),
             grid: Arc::new(RwLock::new(grid)),
-            broadcast,
-            queue,
-        }
+            broadcast: Arc::new(Mutex::new(tx)),
+            queue: Arc::new(Mutex::new(PointQueue::new())),
+            history: Arc::new(RwLock::new(History::new(config.broadcast.history_size))),
+            seq: Arc::new(AtomicU64::new(0)),
+            epoch: audit::epoch_after(last_epoch),
+            connections: Arc::new(Mutex::new(Connections::new())),
+            presence: Arc::new(Mutex::new(presence_tx)),
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::new())),
+            snapshots: Arc::new(Mutex::new(SnapshotCache::default())),
+            tiles: Arc::new(Mutex::new(Tiles::new(config.canvas))),
+            modified: Arc::new(RwLock::new(HashMap::new())),
+            mode: watch::Sender::new(BoardMode {
+                mode: config.mode,
+                notice: config.notice.clone(),
+            }),
+            regions: Arc::new(RwLock::new(Regions::new(config.canvas))),
+            audit: AuditWriter::spawn(config.audit.clone()),
+        };
+        tokio::spawn(broadcast_timer(state.clone(), config.broadcast));
+        tokio::spawn(presence_timer(state.clone(), config.presence));
+        state
     }
 
     pub async fn load(&mut self) {
         let mut grid = self.grid.write().await;
-        let data = fs::read(&self.dump_path).unwrap_or_default();
-        if let Ok(data) = BASE64_STANDARD.decode(&data) {
-            if let Ok(data) = data.try_into() {
-                grid.set_full(data).await
-            }
+        if let Ok(board) = read_dump(&self.dump_path) {
+            grid.set_full(board).await
+        }
+        drop(grid);
+        if let Err(err) = self.load_regions().await {
+            log::error!(
+                "Failed to read protected regions from {}: {}",
+                self.config.storage.regions_path,
+                err
+            );
         }
     }
 
-    pub async fn save(&self) -> String {
-        let grid = self.grid.read().await;
-        BASE64_STANDARD.encode(grid.get_full().await)
+    /// Writes the dump and the PNG snapshot, returning the sequence number
+    /// the dump reflects.
+    pub async fn dump(&self) -> io::Result<u64> {
+        let (seq, board) = self.snapshot().await;
+        fs::write(&self.dump_path, BASE64_STANDARD.encode(board))?;
+        self.save_png(&self.bitmap_path).await;
+        Ok(seq)
     }
 
     pub async fn save_png(&self, filename: &str) {
         let buffer = self.grid.read().await.get_full().await;
 
-        let mut imgbuf = image::ImageBuffer::new(1000, 1000);
+        let canvas = self.config.canvas;
+        let image = tiles::render(&buffer, canvas, self.config.colors, Window::full(canvas));
+        let imgbuf = DynamicImage::ImageRgba8(image).to_rgb8();
 
-        let filled_color = image::Rgb([255u8, 0u8, 0u8]);
-        let empty_color = image::Rgb([255u8, 255u8, 255u8]);
-
-        for i in 0..MAX_SIZE * 8 {
-            let bit_index = i % 8;
-            let byte_index = i / 8;
-            let byte = buffer[byte_index];
-            let bit_value = get_bit(byte, bit_index);
-
-            let x = i % 1000;
-            let y = i / 1000;
-            imgbuf.put_pixel(
-                x as u32,
-                y as u32,
-                if bit_value { filled_color } else { empty_color },
-            );
+        if let Err(err) = imgbuf.save(filename) {
+            log::error!("Failed to write bitmap to {}: {}", filename, err);
         }
-
-        imgbuf.save(filename);
     }
 }
 
-async fn broadcast_timer(
-    queue: Arc<Mutex<PointQueue>>,
-    tx: Arc<Mutex<broadcast::Sender<Message>>>,
-) {
-    let mut interval = time::interval(Duration::from_millis(5000));
-    loop {
-        interval.tick().await;
-        let mut points = queue.lock().await;
-
-        if points.is_empty() {
-            continue;
-        }
-
-        let points2 = PointQueue {
-            on: points.on.clone(),
-            off: points.off.clone(),
-        };
-
-        let message = serde_json::to_string(&points2);
-        match message {
-            Ok(text) => {
-                if let Err(err) = tx.lock().await.send(Message::Text(text)) {
-                    log::warn!("Failed to broadcast a message, {}", err);
-                }
-            }
-            Err(_) => {
-                continue;
-            }
-        }
+/// Pixels of the canvas whose value in `board` differs from `current`.
+pub fn differing(
+    current: &[u8; MAX_SIZE],
+    board: &[u8; MAX_SIZE],
+    canvas: Canvas,
+) -> Vec<(usize, bool)> {
+    (0..canvas.pixels())
+        .filter_map(|index| {
+            let (byte, bit) = (index / 8, index % 8);
+            let value = get_bit(board[byte], bit);
+            (get_bit(current[byte], bit) != value).then_some((index, value))
+        })
+        .collect()
+}
 
-        points.clear();
-    }
+/// Reads a board saved by [`AppState::dump`].
+pub fn read_dump(path: &str) -> io::Result<[u8; MAX_SIZE]> {
+    let data = fs::read(path)?;
+    let data = BASE64_STANDARD
+        .decode(data.trim_ascii())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    data.try_into().map_err(|data: Vec<u8>| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Expected {} bytes, found {}", MAX_SIZE, data.len()),
+        )
+    })
 }
@@ -0,0 +1,308 @@
+use std::{collections::HashMap, io::Cursor, ops::Range, sync::Arc};
+
+use bytes::Bytes;
+use image::{ImageError, ImageFormat, Rgba, RgbaImage};
+
+use crate::{
+    bit_utils::get_bit,
+    config::{Color, Colors},
+    grid::{Canvas, Grid, MAX_SIZE},
+    state::AppState,
+};
+
+pub const TILE_SIZE: usize = 256;
+const OUTSIDE: [u8; 4] = [0, 0, 0, 0];
+
+/// Part of the board to draw: `width` × `height` image pixels starting at
+/// board pixel (`x`, `y`), each covering `scale` × `scale` board pixels.
+#[derive(Clone, Copy, Debug)]
+pub struct Window {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub scale: usize,
+}
+
+impl Window {
+    /// The whole canvas at full resolution.
+    pub fn full(canvas: Canvas) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: canvas.width,
+            height: canvas.height,
+            scale: 1,
+        }
+    }
+}
+
+/// Draws a window of the board, shading every image pixel by the share of
+/// the board pixels under it that are set.
+pub fn render(board: &[u8; MAX_SIZE], canvas: Canvas, colors: Colors, window: Window) -> RgbaImage {
+    let (filled, empty) = (rgba(colors.filled), rgba(colors.empty));
+    let scale = window.scale;
+    RgbaImage::from_fn(window.width as u32, window.height as u32, |px, py| {
+        let left = window.x + px as usize * scale;
+        let top = window.y + py as usize * scale;
+        let right = (left + scale).min(canvas.width);
+        let bottom = (top + scale).min(canvas.height);
+        if left >= right || top >= bottom {
+            return Rgba(OUTSIDE);
+        }
+        let mut set = 0;
+        for row in top..bottom {
+            for col in left..right {
+                let i = row * canvas.width + col;
+                set += usize::from(get_bit(board[i / 8], i % 8));
+            }
+        }
+        let total = (right - left) * (bottom - top);
+        Rgba(std::array::from_fn(|channel| {
+            let (empty, filled) = (empty[channel] as usize, filled[channel] as usize);
+            let shade = (empty * (total - set) + filled * set + total / 2) / total;
+            shade as u8
+        }))
+    })
+}
+
+fn rgba(Color([r, g, b]): Color) -> [u8; 4] {
+    [r, g, b, 255]
+}
+
+pub fn encode_png(image: &RgbaImage) -> Result<Vec<u8>, ImageError> {
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TileId {
+    pub z: u32,
+    pub x: usize,
+    pub y: usize,
+}
+
+/// How a canvas is cut into tiles. At the top zoom level one tile pixel is
+/// one board pixel, every level below halves the resolution until the
+/// canvas fits in a single tile.
+#[derive(Clone, Copy, Debug)]
+struct Pyramid {
+    canvas: Canvas,
+    /// Full resolution tiles across and down
+    columns: usize,
+    rows: usize,
+    max_zoom: u32,
+}
+
+impl Pyramid {
+    fn new(canvas: Canvas) -> Self {
+        let columns = canvas.width.div_ceil(TILE_SIZE);
+        let rows = canvas.height.div_ceil(TILE_SIZE);
+        Self {
+            canvas,
+            columns,
+            rows,
+            max_zoom: columns.max(rows).next_power_of_two().trailing_zeros(),
+        }
+    }
+
+    /// The tile, if it exists at that zoom level.
+    fn tile(&self, z: u32, x: usize, y: usize) -> Option<TileId> {
+        if z > self.max_zoom {
+            return None;
+        }
+        let id = TileId { z, x, y };
+        let span = TILE_SIZE * self.scale(id);
+        let exists = x < self.canvas.width.div_ceil(span) && y < self.canvas.height.div_ceil(span);
+        exists.then_some(id)
+    }
+
+    /// Board pixels per tile pixel along each side.
+    fn scale(&self, id: TileId) -> usize {
+        1 << (self.max_zoom - id.z)
+    }
+
+    fn window(&self, id: TileId) -> Window {
+        let scale = self.scale(id);
+        let span = TILE_SIZE * scale;
+        Window {
+            x: id.x * span,
+            y: id.y * span,
+            width: TILE_SIZE,
+            height: TILE_SIZE,
+            scale,
+        }
+    }
+
+    /// Columns and rows of full resolution tiles this tile covers.
+    fn native(&self, id: TileId) -> (Range<usize>, Range<usize>) {
+        let scale = self.scale(id);
+        let columns = id.x * scale..((id.x + 1) * scale).min(self.columns);
+        let rows = id.y * scale..((id.y + 1) * scale).min(self.rows);
+        (columns, rows)
+    }
+}
+
+pub struct Tile {
+    /// Sequence number of the last change inside the tile
+    pub version: u64,
+    pub png: Bytes,
+}
+
+/// Rendered tiles together with what they have to be rendered again for.
+///
+/// Versions are tracked for full resolution tiles only, a zoomed out tile
+/// is as new as the newest tile it covers.
+pub struct Tiles {
+    pyramid: Pyramid,
+    versions: Vec<u64>,
+    rendered: HashMap<TileId, Arc<Tile>>,
+}
+
+impl Tiles {
+    pub fn new(canvas: Canvas) -> Self {
+        let pyramid = Pyramid::new(canvas);
+        Self {
+            pyramid,
+            versions: vec![0; pyramid.columns * pyramid.rows],
+            rendered: HashMap::new(),
+        }
+    }
+
+    /// Marks the tile holding the pixel as changed at `seq`.
+    pub fn touch(&mut self, index: usize, seq: u64) {
+        let (x, y) = self.pyramid.canvas.position(index);
+        let native = y / TILE_SIZE * self.pyramid.columns + x / TILE_SIZE;
+        if let Some(version) = self.versions.get_mut(native) {
+            *version = seq;
+        }
+    }
+
+    pub fn version(&self, id: TileId) -> u64 {
+        let (columns, rows) = self.pyramid.native(id);
+        let per_row = self.pyramid.columns;
+        rows.flat_map(|row| columns.clone().map(move |col| row * per_row + col))
+            .map(|native| self.versions[native])
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn fresh(&self, id: TileId) -> Option<Arc<Tile>> {
+        self.rendered
+            .get(&id)
+            .filter(|tile| tile.version == self.version(id))
+            .cloned()
+    }
+
+    fn store(&mut self, id: TileId, tile: Arc<Tile>) {
+        let newer = self
+            .rendered
+            .get(&id)
+            .is_none_or(|cached| cached.version < tile.version);
+        if newer {
+            self.rendered.insert(id, tile);
+        }
+    }
+}
+
+impl AppState {
+    /// The tile as PNG, rendered again only when pixels inside it changed.
+    /// `None` when the canvas has no such tile.
+    pub async fn tile(&self, z: u32, x: usize, y: usize) -> Result<Option<Arc<Tile>>, ImageError> {
+        let (id, window, version, board) = {
+            let grid = self.grid.read().await;
+            let tiles = self.tiles.lock().await;
+            let Some(id) = tiles.pyramid.tile(z, x, y) else {
+                return Ok(None);
+            };
+            if let Some(tile) = tiles.fresh(id) {
+                return Ok(Some(tile));
+            }
+            let window = tiles.pyramid.window(id);
+            (id, window, tiles.version(id), grid.get_full().await)
+        };
+
+        let image = render(&board, self.config.canvas, self.config.colors, window);
+        let tile = Arc::new(Tile {
+            version,
+            png: encode_png(&image)?.into(),
+        });
+        log::debug!("Rendered tile {:?} at version {}", id, version);
+        self.tiles.lock().await.store(id, tile.clone());
+        Ok(Some(tile))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pyramid_covers_board() {
+        let pyramid = Pyramid::new(Canvas::default());
+        assert_eq!(2, pyramid.max_zoom);
+        assert!(pyramid.tile(0, 0, 0).is_some());
+        assert!(pyramid.tile(0, 1, 0).is_none());
+        assert!(pyramid.tile(1, 1, 1).is_some());
+        assert!(pyramid.tile(2, 3, 3).is_some());
+        assert!(pyramid.tile(2, 4, 0).is_none());
+        assert!(pyramid.tile(3, 0, 0).is_none());
+
+        let strip = Pyramid::new(Canvas {
+            width: 2000,
+            height: 200,
+        });
+        assert_eq!(3, strip.max_zoom);
+        assert!(strip.tile(3, 7, 0).is_some());
+        assert!(strip.tile(3, 0, 1).is_none());
+        assert!(strip.tile(0, 0, 0).is_some());
+    }
+
+    #[test]
+    fn zoomed_out_tiles_follow_newest_change() {
+        let canvas = Canvas::default();
+        let mut tiles = Tiles::new(canvas);
+        let id = |z, x, y| tiles.pyramid.tile(z, x, y).unwrap();
+        let (native, neighbour, half, other_half, whole) = (
+            id(2, 2, 1),
+            id(2, 1, 1),
+            id(1, 1, 0),
+            id(1, 0, 0),
+            id(0, 0, 0),
+        );
+
+        // Pixel (600, 300) sits in full resolution tile (2, 1)
+        tiles.touch(canvas.index(600, 300).unwrap(), 7);
+        assert_eq!(7, tiles.version(native));
+        assert_eq!(0, tiles.version(neighbour));
+        assert_eq!(7, tiles.version(half));
+        assert_eq!(0, tiles.version(other_half));
+        assert_eq!(7, tiles.version(whole));
+    }
+
+    #[test]
+    fn render_shades_by_density() {
+        let canvas = Canvas::default();
+        let colors = Colors::default();
+        let window = |x, width, height, scale| Window {
+            x,
+            y: 0,
+            width,
+            height,
+            scale,
+        };
+        let mut board = [0u8; MAX_SIZE];
+        // Two of the four pixels in the top left 2x2 block
+        board[0] = 0b11;
+        let image = render(&board, canvas, colors, window(0, 2, 2, 2));
+        assert_eq!(&Rgba([255, 128, 128, 255]), image.get_pixel(0, 0));
+        assert_eq!(&Rgba(rgba(colors.empty)), image.get_pixel(1, 0));
+
+        let image = render(&board, canvas, colors, window(0, 2, 1, 1));
+        assert_eq!(&Rgba(rgba(colors.filled)), image.get_pixel(0, 0));
+
+        let image = render(&board, canvas, colors, window(996, 2, 1, 4));
+        assert_eq!(&Rgba(OUTSIDE), image.get_pixel(1, 0));
+    }
+}
@@ -0,0 +1,230 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::grid::{Canvas, MAX_SIZE};
+
+/// Pixels the board can hold, the canvas uses all or part of them
+pub const PIXELS: usize = MAX_SIZE * 8;
+
+/// Linear index of the pixel at `x`, `y` if it is on the canvas.
+pub fn pixel_index(canvas: Canvas, x: usize, y: usize) -> Result<usize, WriteError> {
+    canvas.index(x, y).ok_or(WriteError::OutOfRange)
+}
+
+/// Marks a websocket frame as a batch of [`PixelWrite`]s.
+pub const BATCH_FRAME: u8 = 0x01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Set(bool),
+    Toggle,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PixelWrite {
+    pub index: usize,
+    pub op: Op,
+    /// Value the pixel must have for the batch to go through
+    pub expect: Option<bool>,
+}
+
+impl PixelWrite {
+    /// Decodes `BATCH_FRAME` followed by entries of a 3 byte little endian
+    /// index and an op byte: 0 clears, 1 sets and 2 toggles the pixel.
+    pub fn decode_batch(
+        frame: &[u8],
+        canvas: Canvas,
+        max_batch: usize,
+    ) -> Result<Vec<PixelWrite>, WriteError> {
+        let Some((&BATCH_FRAME, entries)) = frame.split_first() else {
+            return Err(WriteError::InvalidBatch);
+        };
+        if entries.is_empty() || entries.len() % 4 != 0 || entries.len() / 4 > max_batch {
+            return Err(WriteError::InvalidBatch);
+        }
+
+        entries
+            .chunks_exact(4)
+            .map(|entry| {
+                let index =
+                    entry[0] as usize + ((entry[1] as usize) << 8) + ((entry[2] as usize) << 16);
+                let op = match entry[3] {
+                    0 => Op::Set(false),
+                    1 => Op::Set(true),
+                    2 => Op::Toggle,
+                    _ => return Err(WriteError::InvalidBatch),
+                };
+                if !canvas.contains(index) {
+                    return Err(WriteError::InvalidBatch);
+                }
+                Ok(PixelWrite {
+                    index,
+                    op,
+                    expect: None,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A set of pixel writes that succeed or fail together.
+#[derive(Deserialize)]
+pub struct Transaction {
+    pub writes: Vec<TransactionWrite>,
+}
+
+#[derive(Deserialize)]
+pub struct TransactionWrite {
+    pub x: usize,
+    pub y: usize,
+    pub value: bool,
+    #[serde(default)]
+    pub expect: Option<bool>,
+}
+
+impl Transaction {
+    pub fn into_writes(
+        self,
+        canvas: Canvas,
+        max_batch: usize,
+    ) -> Result<Vec<PixelWrite>, WriteError> {
+        if self.writes.is_empty() || self.writes.len() > max_batch {
+            return Err(WriteError::InvalidBatch);
+        }
+        self.writes
+            .into_iter()
+            .map(|write| {
+                let index = canvas
+                    .index(write.x, write.y)
+                    .ok_or(WriteError::InvalidBatch)?;
+                Ok(PixelWrite {
+                    index,
+                    op: Op::Set(write.value),
+                    expect: write.expect,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Outcome of an applied batch.
+#[derive(Debug, Serialize)]
+pub struct Applied {
+    /// Sequence number of the batch, unset when no pixel changed
+    pub seq: Option<u64>,
+    pub changed: usize,
+}
+
+/// Why a write was not applied.
+#[derive(Debug, PartialEq)]
+pub enum WriteError {
+    RateLimited {
+        retry_after: Duration,
+    },
+    /// More pixels than the rate limit allows at once
+    TooLarge,
+    InvalidBatch,
+    InvalidStroke,
+    /// Pixels whose value did not match the expected one
+    Conflict {
+        pixels: Vec<usize>,
+    },
+    OutOfRange,
+    Banned,
+    /// The board is closed for writes, see [`crate::mode::Mode`]
+    ReadOnly,
+    Maintenance,
+    /// Pixels inside protected regions
+    Protected {
+        pixels: Vec<usize>,
+    },
+}
+
+impl WriteError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            WriteError::RateLimited { .. } => "rate_limited",
+            WriteError::TooLarge => "too_large",
+            WriteError::InvalidBatch => "invalid_batch",
+            WriteError::InvalidStroke => "invalid_stroke",
+            WriteError::Conflict { .. } => "conflict",
+            WriteError::OutOfRange => "out_of_range",
+            WriteError::Banned => "banned",
+            WriteError::ReadOnly => "read_only",
+            WriteError::Maintenance => "maintenance",
+            WriteError::Protected { .. } => "protected",
+        }
+    }
+
+    pub fn conflicts(&self) -> &[usize] {
+        match self {
+            WriteError::Conflict { pixels } => pixels,
+            _ => &[],
+        }
+    }
+
+    pub fn protected(&self) -> &[usize] {
+        match self {
+            WriteError::Protected { pixels } => pixels,
+            _ => &[],
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            WriteError::RateLimited { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_batch_frame() {
+        let frame = [BATCH_FRAME, 0x3f, 0x42, 0x0f, 1, 5, 0, 0, 2];
+        assert_eq!(
+            Ok(vec![
+                PixelWrite {
+                    index: 999_999,
+                    op: Op::Set(true),
+                    expect: None,
+                },
+                PixelWrite {
+                    index: 5,
+                    op: Op::Toggle,
+                    expect: None,
+                },
+            ]),
+            PixelWrite::decode_batch(&frame, Canvas::default(), 10)
+        );
+    }
+
+    #[test]
+    fn decode_batch_rejects_whole_frame() {
+        let invalid: [&[u8]; 6] = [
+            &[],
+            &[BATCH_FRAME],
+            &[0x02, 5, 0, 0, 1],
+            &[BATCH_FRAME, 5, 0, 0],
+            &[BATCH_FRAME, 5, 0, 0, 1, 0x40, 0x42, 0x0f, 3],
+            &[BATCH_FRAME, 5, 0, 0, 1, 0x40, 0x42, 0x0f, 1],
+        ];
+        for frame in invalid {
+            assert_eq!(
+                Err(WriteError::InvalidBatch),
+                PixelWrite::decode_batch(frame, Canvas::default(), 10),
+                "{:?}",
+                frame
+            );
+        }
+        let too_long = [BATCH_FRAME, 1, 0, 0, 1, 2, 0, 0, 1];
+        assert_eq!(
+            Err(WriteError::InvalidBatch),
+            PixelWrite::decode_batch(&too_long, Canvas::default(), 1)
+        );
+    }
+}
@@ -1,88 +1,380 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{future, net::IpAddr, sync::Arc, time::Duration};
 
 use axum::{
     extract::{
-        ws::{Message, WebSocket},
-        ConnectInfo, State, WebSocketUpgrade,
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        State, WebSocketUpgrade,
     },
     response::IntoResponse,
 };
 use futures::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
+    stream::{self, SplitSink, SplitStream},
+    Sink, SinkExt, StreamExt,
+};
+use serde::Deserialize;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        Mutex,
+    },
+    time::{self, Instant},
+};
+
+use crate::{
+    broadcast::Event,
+    client::Client,
+    presence::Refusal,
+    raster,
+    state::AppState,
+    write::{Op, PixelWrite, Transaction, WriteError, BATCH_FRAME},
 };
-use tokio::sync::{broadcast, Mutex};
 
-use crate::state::AppState;
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Cursor {
+        x: u32,
+        y: u32,
+    },
+    /// Freehand line through the points, drawn or erased as a whole
+    Stroke {
+        points: Vec<(u32, u32)>,
+        size: u32,
+        value: bool,
+    },
+    Transaction(Transaction),
+}
 
 pub async fn ws_grid(
     ws: WebSocketUpgrade,
     //user_agent: Option<TypedHeader<headers::UserAgent>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    client: Client,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
     log::debug!("connecting ws");
     // finalize the upgrade process by returning upgrade callback.
     // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_ws(socket, addr, state))
+    ws.on_upgrade(move |socket| handle_ws(socket, client.ip, state))
 }
 
-async fn handle_ws(socket: WebSocket, addr: SocketAddr, state: AppState) {
-    //TODO: check if already connected
-    log::debug!("Connected ws from: {}", addr);
-    let (sender, receiver) = socket.split();
+async fn handle_ws(socket: WebSocket, ip: IpAddr, state: AppState) {
+    log::debug!("Connected ws from: {}", ip);
+    let (mut sender, receiver) = socket.split();
+
+    let admitted = if state.live.borrow().is_banned(ip) {
+        Err(Refusal::Banned)
+    } else {
+        let mut connections = state.connections.lock().await;
+        connections
+            .admit(ip, &state.config.connections)
+            .map(|session| (session, connections.count()))
+    };
+    let (session, online) = match admitted {
+        Ok(admitted) => admitted,
+        Err(refusal) => {
+            log::info!("Refusing ws from {}: {:?}", ip, refusal);
+            let _ = sender
+                .send(Message::Close(Some(refusal.close_frame())))
+                .await;
+            return;
+        }
+    };
+    let hello = Event::hello(session, state.config.canvas, online);
+    let mut greeting = stream::iter(
+        [Some(hello), state.mode_notice()]
+            .into_iter()
+            .flatten()
+            .map(|event| Ok(Message::Text(event.data.to_string()))),
+    );
+    if sender.send_all(&mut greeting).await.is_err() {
+        state.connections.lock().await.unregister(session);
+        return;
+    }
 
     let sender = Arc::new(Mutex::new(sender));
-    {
-        let broadcast_receiver = state.broadcast.lock().await.subscribe();
-        tokio::spawn(async move {
-            recv_broadcast(sender, broadcast_receiver).await;
-        });
+    let mut broadcast_task = {
+        let board_receiver = state.broadcast.lock().await.subscribe();
+        let presence_receiver = state.presence.lock().await.subscribe();
+        tokio::spawn(recv_broadcast(
+            sender.clone(),
+            board_receiver,
+            presence_receiver,
+            state.clone(),
+        ))
+    };
+    let client = Client::ws(ip, session);
+    let ended = tokio::select! {
+        ended = read(receiver, sender.clone(), &state, client) => ended,
+        // Only ends once the client stopped taking events
+        _ = &mut broadcast_task => Ended::Stalled,
+    };
+
+    broadcast_task.abort();
+    state.connections.lock().await.unregister(session);
+    if let Some(reason) = ended.close_reason() {
+        log::debug!("Closing ws session {}: {}", session, reason);
+        let frame = CloseFrame {
+            code: close_code::AWAY,
+            reason: reason.into(),
+        };
+        let timeout = state.config.heartbeat.pong_timeout;
+        send_within(&sender, Message::Close(Some(frame)), timeout).await;
     }
-    read(receiver, state).await;
 }
 
-async fn read(mut receiver: SplitStream<WebSocket>, state: AppState) {
-    while let Some(Ok(msg)) = receiver.next().await {
-        match msg {
-            Message::Ping(_) => {
-                log::debug!("Got ping");
-            }
-            Message::Pong(_) => {
-                log::debug!("Got pong");
+/// Sends `msg` unless the client takes nothing for `timeout`, like a
+/// half-open connection does once the buffers are full.
+async fn send_within<S>(sender: &Mutex<S>, msg: Message, timeout: Duration) -> bool
+where
+    S: Sink<Message> + Unpin,
+{
+    let send = async { sender.lock().await.send(msg).await.is_ok() };
+    time::timeout(timeout, send).await.unwrap_or(false)
+}
+
+/// Why the server stopped reading from a websocket.
+enum Ended {
+    Disconnected,
+    PongTimeout,
+    /// Sending to the client timed out
+    Stalled,
+    Idle,
+    SessionExpired,
+}
+
+impl Ended {
+    fn close_reason(&self) -> Option<&'static str> {
+        match self {
+            // Nobody is listening on the other end
+            Ended::Disconnected | Ended::PongTimeout | Ended::Stalled => None,
+            Ended::Idle => Some("Idle for too long"),
+            Ended::SessionExpired => Some("Session expired"),
+        }
+    }
+}
+
+async fn read(
+    mut receiver: SplitStream<WebSocket>,
+    sender: Arc<Mutex<SplitSink<WebSocket, Message>>>,
+    state: &AppState,
+    client: Client,
+) -> Ended {
+    let config = &state.config.heartbeat;
+    let started = Instant::now();
+    let mut ping = time::interval_at(started + config.ping_interval, config.ping_interval);
+    let mut pong_deadline: Option<Instant> = None;
+    let mut last_activity = started;
+
+    loop {
+        let idle_deadline = config.idle_timeout.map(|timeout| last_activity + timeout);
+        let session_deadline = config.max_session.map(|max| started + max);
+
+        tokio::select! {
+            msg = receiver.next() => {
+                let Some(Ok(msg)) = msg else {
+                    return Ended::Disconnected;
+                };
+                match msg {
+                    Message::Ping(_) => {
+                        log::debug!("Got ping");
+                    }
+                    Message::Pong(_) => {
+                        log::debug!("Got pong");
+                        pong_deadline = None;
+                    }
+                    Message::Close(_) => {
+                        log::debug!("Disconnecting");
+                        return Ended::Disconnected;
+                    }
+                    msg => {
+                        last_activity = Instant::now();
+                        let reply = match handle_message(msg, state, client).await {
+                            Ok(reply) => reply,
+                            Err(err) => Some(Event::rejected(&err)),
+                        };
+                        if let Some(reply) = reply {
+                            let reply = Message::Text(reply.data.to_string());
+                            if !send_within(&sender, reply, config.pong_timeout).await {
+                                return Ended::Stalled;
+                            }
+                        }
+                    }
+                }
             }
-            Message::Binary(bin) => {
-                if bin.len() < 3 {
-                    log::warn!("Wrong message, len is {}", bin.len());
+            _ = ping.tick() => {
+                if pong_deadline.is_some() {
                     continue;
                 }
-                let b0: usize = bin.get(0).cloned().unwrap_or(0) as usize;
-                let b1: usize = bin.get(1).cloned().unwrap_or(0) as usize;
-                let b2: usize = bin.get(2).cloned().unwrap_or(0) as usize;
-                let index = b0 + (b1 << 8) + (b2 << 16);
-                if index >= 1_000_000 {
-                    continue;
+                if !send_within(&sender, Message::Ping(vec![]), config.pong_timeout).await {
+                    return Ended::Stalled;
                 }
-                state.toggle(index).await;
+                pong_deadline = Some(Instant::now() + config.pong_timeout);
             }
-            Message::Close(_) => {
-                log::debug!("Disconnecting");
-                return;
+            _ = sleep_until_some(pong_deadline) => {
+                log::debug!("No pong from ws session {:?}", client.session);
+                return Ended::PongTimeout;
             }
-            _ => {}
+            _ = sleep_until_some(idle_deadline) => return Ended::Idle,
+            _ = sleep_until_some(session_deadline) => return Ended::SessionExpired,
         }
+    }
+}
 
-        log::debug!("Broadcasting");
+async fn sleep_until_some(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => future::pending().await,
     }
 }
 
+/// Handles one client message, returning a reply meant only for that client.
+async fn handle_message(
+    msg: Message,
+    state: &AppState,
+    client: Client,
+) -> Result<Option<Event>, WriteError> {
+    match msg {
+        Message::Binary(bin) if bin.len() > 3 && bin[0] == BATCH_FRAME => {
+            let writes =
+                PixelWrite::decode_batch(&bin, state.config.canvas, state.config.max_batch)?;
+            state.apply(client, &writes).await?;
+        }
+        // Older clients send the bare index of a pixel to toggle
+        Message::Binary(bin) => {
+            if bin.len() < 3 {
+                log::warn!("Wrong message, len is {}", bin.len());
+                return Ok(None);
+            }
+            let b0: usize = bin.first().cloned().unwrap_or(0) as usize;
+            let b1: usize = bin.get(1).cloned().unwrap_or(0) as usize;
+            let b2: usize = bin.get(2).cloned().unwrap_or(0) as usize;
+            let index = b0 + (b1 << 8) + (b2 << 16);
+            if !state.config.canvas.contains(index) {
+                return Ok(None);
+            }
+            state.toggle(client, index).await?;
+        }
+        Message::Text(text) => match serde_json::from_str(&text) {
+            Ok(ClientMessage::Cursor { x, y }) => {
+                if state.config.canvas.index(x as usize, y as usize).is_some() {
+                    if let Some(session) = client.session {
+                        state.connections.lock().await.move_cursor(session, x, y);
+                    }
+                }
+            }
+            Ok(ClientMessage::Stroke {
+                points,
+                size,
+                value,
+            }) => {
+                let pixels =
+                    raster::stroke(&points, size, state.config.canvas, state.config.max_batch)
+                        .ok_or(WriteError::InvalidStroke)?;
+                let writes: Vec<PixelWrite> = pixels
+                    .into_iter()
+                    .map(|index| PixelWrite {
+                        index,
+                        op: Op::Set(value),
+                        expect: None,
+                    })
+                    .collect();
+                state.apply(client, &writes).await?;
+            }
+            Ok(ClientMessage::Transaction(transaction)) => {
+                let writes =
+                    transaction.into_writes(state.config.canvas, state.config.max_batch)?;
+                let applied = state.apply(client, &writes).await?;
+                return Ok(Some(Event::committed(&applied)));
+            }
+            Err(err) => {
+                log::debug!("Unknown text message: {}", err);
+            }
+        },
+        _ => {}
+    }
+    Ok(None)
+}
+
 async fn recv_broadcast(
     client_tx: Arc<Mutex<SplitSink<WebSocket, Message>>>,
-    mut broadcast_receiver: broadcast::Receiver<Message>,
+    mut board_receiver: broadcast::Receiver<Event>,
+    mut presence_receiver: broadcast::Receiver<Event>,
+    state: AppState,
 ) {
-    while let Ok(msg) = broadcast_receiver.recv().await {
-        if client_tx.lock().await.send(msg).await.is_err() {
-            return; // disconnected.
+    loop {
+        let event = tokio::select! {
+            event = board_receiver.recv() => match event {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    log::debug!("Client lagged by {} batches, resyncing", skipped);
+                    let (seq, board) = state.snapshot().await;
+                    Event::snapshot(seq, &board)
+                }
+                Err(RecvError::Closed) => return,
+            },
+            event = presence_receiver.recv() => match event {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            },
+        };
+        let msg = Message::Text(event.data.to_string());
+        if !send_within(&client_tx, msg, state.config.heartbeat.pong_timeout).await {
+            return; // disconnected or stalled
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use futures::sink;
+
+    use super::*;
+    use crate::{config::Config, grid::Grid};
+
+    #[tokio::test]
+    async fn binary_frames_without_batch_opcode_toggle() {
+        let state = AppState::new(Config::scratch("ws-binary"));
+        let client = Client::ws(IpAddr::from([127, 0, 0, 1]), 1);
+
+        // Index 1000 is 0xe8 0x03 0x00, extra bytes were always ignored
+        let legacy = Message::Binary(vec![0xe8, 0x03, 0x00, 0x07]);
+        assert!(matches!(
+            handle_message(legacy, &state, client).await,
+            Ok(None)
+        ));
+        let batch = Message::Binary(vec![BATCH_FRAME, 0x05, 0x00, 0x00, 0x01]);
+        assert!(matches!(
+            handle_message(batch, &state, client).await,
+            Ok(None)
+        ));
+
+        let grid = state.grid.read().await;
+        assert!(grid.read_item(1000).await);
+        assert!(grid.read_item(5).await);
+        assert!(!grid.read_item(BATCH_FRAME as usize).await);
+    }
+
+    #[tokio::test]
+    async fn stalled_client_times_out() {
+        let timeout = Duration::from_millis(50);
+        let drain = Mutex::new(sink::drain());
+        assert!(send_within(&drain, Message::Ping(vec![]), timeout).await);
+
+        // Takes nothing, like a half-open socket with full buffers
+        let stuck = sink::unfold((), |(), _: Message| {
+            future::pending::<Result<(), Infallible>>()
+        });
+        let stuck = Mutex::new(Box::pin(stuck));
+        let started = Instant::now();
+        assert!(!send_within(&stuck, Message::Ping(vec![]), timeout).await);
+        assert!(started.elapsed() >= timeout);
+
+        // Nor does a send queued behind the stuck one get through
+        let held = stuck.lock().await;
+        assert!(!send_within(&stuck, Message::Ping(vec![]), timeout).await);
+        drop(held);
+    }
+}